@@ -0,0 +1,463 @@
+
+use hash;
+use script;
+use util;
+
+/**
+ * Networks, which determine address prefixes
+ */
+pub enum Network {
+  Mainnet,
+  Testnet,
+  Signet,
+  Regtest
+}
+
+/**
+ * Parse a network name as given on the command line
+ */
+pub fn network_from_str (name: &str) -> Option<Network>
+{
+  match name {
+    "mainnet" | "main" => Some(Mainnet),
+    "testnet" | "test" => Some(Testnet),
+    "signet" => Some(Signet),
+    "regtest" => Some(Regtest),
+    _ => None
+  }
+}
+
+impl Network {
+  /** Base58 version byte for P2PKH addresses */
+  fn pubkey_hash_prefix (&self) -> u8 {
+    match *self { Mainnet => 0x00, _ => 0x6f }
+  }
+
+  /** Base58 version byte for P2SH addresses */
+  fn script_hash_prefix (&self) -> u8 {
+    match *self { Mainnet => 0x05, _ => 0xc4 }
+  }
+
+  /** Human-readable part of segwit addresses */
+  fn bech32_hrp (&self) -> &'static str {
+    match *self { Mainnet => "bc", Testnet | Signet => "tb", Regtest => "bcrt" }
+  }
+}
+
+
+/* BASE58CHECK */
+
+static BASE58_CHARS: &'static str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/**
+ * Encode data, followed by a 4-byte double-SHA256 checksum, as base58
+ */
+pub fn base58check_encode (data: &[u8]) -> ~str
+{
+  let mut payload = data.to_owned();
+  let checksum = hash::sha256d_sum (data);
+  payload.push_all (checksum.slice (0, 4));
+
+  /* Convert to base 58, stored little-endian */
+  let mut digits: ~[u8] = ~[];
+  for &byte in payload.iter() {
+    let mut carry = byte as uint;
+    for digit in digits.mut_iter() {
+      carry += (*digit as uint) << 8;
+      *digit = (carry % 58) as u8;
+      carry /= 58;
+    }
+    while carry > 0 {
+      digits.push ((carry % 58) as u8);
+      carry /= 58;
+    }
+  }
+
+  /* Leading zero bytes are encoded as '1's */
+  let mut rv = ~"";
+  for &byte in payload.iter() {
+    if byte != 0 { break; }
+    rv.push_char ('1');
+  }
+  for &digit in digits.rev_iter() {
+    rv.push_char (BASE58_CHARS[digit as uint] as char);
+  }
+  rv
+}
+
+/**
+ * Decode a base58check string, verifying and stripping its checksum
+ */
+pub fn base58check_decode (s: &str) -> Option<~[u8]>
+{
+  /* Convert from base 58, stored little-endian */
+  let mut bytes: ~[u8] = ~[];
+  for &ch in s.as_bytes().iter() {
+    let mut carry = match BASE58_CHARS.as_bytes().iter().position (|&c| c == ch) {
+      Some(n) => n,
+      None => { return None; }
+    };
+    for byte in bytes.mut_iter() {
+      carry += (*byte as uint) * 58;
+      *byte = carry as u8;
+      carry >>= 8;
+    }
+    while carry > 0 {
+      bytes.push (carry as u8);
+      carry >>= 8;
+    }
+  }
+
+  /* Leading '1's are zero bytes */
+  let mut rv: ~[u8] = ~[];
+  for &ch in s.as_bytes().iter() {
+    if ch != '1' as u8 { break; }
+    rv.push (0);
+  }
+  for &byte in bytes.rev_iter() {
+    rv.push (byte);
+  }
+
+  if rv.len() < 4 { return None; }
+  let split = rv.len() - 4;
+  let checksum = hash::sha256d_sum (rv.slice_to (split));
+  if checksum.slice (0, 4) != rv.slice_from (split) { return None; }
+  Some(rv.slice_to (split).to_owned())
+}
+
+
+/* BECH32 AND BECH32M (BIP173, BIP350) */
+
+static BECH32_CHARS: &'static str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+static BECH32_CONST: u32 = 1;
+static BECH32M_CONST: u32 = 0x2bc830a3;
+
+fn bech32_polymod (values: &[u8]) -> u32
+{
+  let generator = [0x3b6a57b2u32, 0x26508e6du32, 0x1ea119fau32, 0x3d4233ddu32, 0x2a1462b3u32];
+  let mut chk = 1u32;
+  for &v in values.iter() {
+    let top = chk >> 25;
+    chk = ((chk & 0x1ffffff) << 5) ^ (v as u32);
+    for i in range (0u, 5) {
+      if (top >> i) & 1 == 1 {
+        chk ^= generator[i];
+      }
+    }
+  }
+  chk
+}
+
+fn bech32_hrp_expand (hrp: &str) -> ~[u8]
+{
+  let mut rv: ~[u8] = ~[];
+  for &ch in hrp.as_bytes().iter() { rv.push (ch >> 5); }
+  rv.push (0);
+  for &ch in hrp.as_bytes().iter() { rv.push (ch & 31); }
+  rv
+}
+
+/**
+ * Regroup a bitstring from `from`-bit groups to `to`-bit groups
+ */
+fn convert_bits (data: &[u8], from: uint, to: uint, pad: bool) -> Option<~[u8]>
+{
+  let mut acc = 0u32;
+  let mut bits = 0u;
+  let mut rv: ~[u8] = ~[];
+  let maxv = (1u32 << to) - 1;
+
+  for &value in data.iter() {
+    if (value as u32) >> from != 0 { return None; }
+    acc = (acc << from) | (value as u32);
+    bits += from;
+    while bits >= to {
+      bits -= to;
+      rv.push (((acc >> bits) & maxv) as u8);
+    }
+  }
+  if pad {
+    if bits > 0 {
+      rv.push (((acc << (to - bits)) & maxv) as u8);
+    }
+  } else if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+    return None;
+  }
+  Some(rv)
+}
+
+/**
+ * Encode a segwit program as a bech32 (v0) or bech32m (v1+) address
+ */
+fn segwit_encode (hrp: &str, version: u8, program: &[u8]) -> ~str
+{
+  let mut data = ~[version];
+  data.push_all (convert_bits (program, 8, 5, true).unwrap());
+
+  let mut values = bech32_hrp_expand (hrp);
+  values.push_all (data);
+  values.push_all (&[0u8, 0, 0, 0, 0, 0]);
+  let constant = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+  let polymod = bech32_polymod (values) ^ constant;
+  for i in range (0u, 6) {
+    data.push (((polymod >> 5 * (5 - i)) & 31) as u8);
+  }
+
+  let mut rv = hrp.to_owned();
+  rv.push_char ('1');
+  for &d in data.iter() {
+    rv.push_char (BECH32_CHARS[d as uint] as char);
+  }
+  rv
+}
+
+/**
+ * Decode a bech32/bech32m segwit address with the given hrp, returning its
+ * version and program
+ */
+fn segwit_decode (hrp: &str, addr: &str) -> Option<(u8, ~[u8])>
+{
+  let bytes = addr.as_bytes();
+  if bytes.len() > 90 { return None; }
+
+  /* Mixed case is forbidden; otherwise work in lowercase */
+  let has_lower = bytes.iter().any (|&c| c >= 'a' as u8 && c <= 'z' as u8);
+  let has_upper = bytes.iter().any (|&c| c >= 'A' as u8 && c <= 'Z' as u8);
+  if has_lower && has_upper { return None; }
+  let lower: ~[u8] = bytes.iter().map (|&c| if c >= 'A' as u8 && c <= 'Z' as u8 { c + 32 } else { c }).collect();
+
+  let sep = match lower.iter().rposition (|&c| c == '1' as u8) {
+    Some(n) => n,
+    None => { return None; }
+  };
+  if sep + 7 > lower.len() || lower.slice_to (sep) != hrp.as_bytes() { return None; }
+
+  let mut data: ~[u8] = ~[];
+  for &ch in lower.slice_from (sep + 1).iter() {
+    match BECH32_CHARS.as_bytes().iter().position (|&c| c == ch) {
+      Some(n) => { data.push (n as u8); }
+      None => { return None; }
+    }
+  }
+
+  let mut values = bech32_hrp_expand (hrp);
+  values.push_all (data);
+  let constant = bech32_polymod (values);
+  if constant != BECH32_CONST && constant != BECH32M_CONST { return None; }
+
+  let data = data.slice_to (data.len() - 6);
+  if data.len() < 1 { return None; }
+  let version = data[0];
+  let program = match convert_bits (data.slice_from (1), 5, 8, false) {
+    Some(p) => p,
+    None => { return None; }
+  };
+
+  if version > 16 || program.len() < 2 || program.len() > 40 { return None; }
+  if version == 0 && program.len() != 20 && program.len() != 32 { return None; }
+  /* v0 uses the original bech32 checksum, everything later bech32m */
+  if (version == 0) != (constant == BECH32_CONST) { return None; }
+  Some((version, program))
+}
+
+
+/* CONVERSION TO AND FROM SCRIPTPUBKEYS */
+
+/**
+ * Encode a scriptPubKey as an address, if it has an address form
+ */
+pub fn from_script (spk: &[u8], network: Network) -> Option<~str>
+{
+  match script::classify (spk) {
+    script::PayToPubkeyHash => {
+      let mut data = ~[network.pubkey_hash_prefix()];
+      data.push_all (spk.slice (3, 23));
+      Some(base58check_encode (data))
+    }
+    script::PayToScriptHash => {
+      let mut data = ~[network.script_hash_prefix()];
+      data.push_all (spk.slice (2, 22));
+      Some(base58check_encode (data))
+    }
+    _ => {
+      match script::witness_program (spk) {
+        /* v0 programs of any other length are unspendable, and have no
+         * address that segwit_decode (or anyone's wallet) would accept */
+        Some((0, ref program)) if program.len() != 20 && program.len() != 32 => None,
+        Some((version, program)) => Some(segwit_encode (network.bech32_hrp(), version, program)),
+        None => None
+      }
+    }
+  }
+}
+
+/**
+ * Decode an address for the given network into its scriptPubKey
+ */
+pub fn to_script (addr: &str, network: Network) -> Option<~[u8]>
+{
+  match segwit_decode (network.bech32_hrp(), addr) {
+    Some((version, program)) => {
+      let mut rv = ~[if version == 0 { script::OP_0 } else { script::OP_1 + version - 1 }];
+      rv.push (program.len() as u8);
+      rv.push_all (program);
+      return Some(rv);
+    }
+    None => {}
+  }
+
+  match base58check_decode (addr) {
+    Some(data) => {
+      if data.len() != 21 { return None; }
+      if data[0] == network.pubkey_hash_prefix() {
+        let mut rv = ~[script::OP_DUP, script::OP_HASH160, 20];
+        rv.push_all (data.slice_from (1));
+        rv.push (script::OP_EQUALVERIFY);
+        rv.push (script::OP_CHECKSIG);
+        Some(rv)
+      } else if data[0] == network.script_hash_prefix() {
+        let mut rv = ~[script::OP_HASH160, 20];
+        rv.push_all (data.slice_from (1));
+        rv.push (script::OP_EQUAL);
+        Some(rv)
+      } else {
+        None
+      }
+    }
+    None => None
+  }
+}
+
+/**
+ * Display a scriptPubKey for humans: its address if it has one, otherwise
+ * its hex
+ */
+pub fn script_to_str (spk: &[u8], network: Network) -> ~str
+{
+  match from_script (spk, network) {
+    Some(addr) => addr,
+    None => util::u8_to_hex_string (spk)
+  }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+  use address::{Mainnet, Testnet, base58check_encode, base58check_decode, from_script, to_script, script_to_str};
+  use util::{hex_string_to_u8, u8_to_hex_string};
+
+  #[test]
+  fn base58check_vectors() {
+    let vectors = [
+      ("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", "0062e907b15cbf27d5425399ebf6f0fb50ebb88f18"),
+      ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", "05b472a266d0bd89c13706a4132ccfb16f7c3b9fcb"),
+      ("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn", "6f243f1394f44554f4ce3fd68649c19adc483ce924")
+    ];
+    for &(addr, data) in vectors.iter() {
+      assert_eq! (base58check_decode (addr).map (|d| u8_to_hex_string (d)), Some(data.to_owned()));
+      assert_eq! (base58check_encode (hex_string_to_u8 (data).unwrap()), addr.to_owned());
+    }
+    /* Bad checksum, and characters which aren't base58 */
+    assert_eq! (base58check_decode ("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"), None);
+    assert_eq! (base58check_decode ("1A1zP1eP5QGefi2DMPTfTL5SLmv7Divf0a"), None);
+    assert_eq! (base58check_decode ("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfIa"), None);
+  }
+
+  #[test]
+  fn base58_addresses() {
+    assert_eq! (to_script ("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Mainnet).map (|s| u8_to_hex_string (s)),
+                Some(~"76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac"));
+    assert_eq! (to_script ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", Mainnet).map (|s| u8_to_hex_string (s)),
+                Some(~"a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87"));
+    /* Wrong network */
+    assert_eq! (to_script ("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Testnet), None);
+    assert_eq! (to_script ("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn", Mainnet), None);
+  }
+
+  /* Valid segwit addresses from BIP350 (which include BIP173's v0 ones) */
+  #[test]
+  fn segwit_valid_vectors() {
+    let vectors = [
+      ("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", true, "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+      ("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7", false,
+       "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"),
+      ("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y", true,
+       "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6"),
+      ("bc1sw50qgdz25j", true, "6002751e"),
+      ("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", true, "5210751e76e8199196d454941c45d1b3a323"),
+      ("tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy", false,
+       "0020000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"),
+      ("tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c", false,
+       "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"),
+      ("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0", true,
+       "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+    ];
+    for &(addr, mainnet, spk) in vectors.iter() {
+      let network = if mainnet { Mainnet } else { Testnet };
+      let script = to_script (addr, network);
+      assert_eq! (script.clone().map (|s| u8_to_hex_string (s)), Some(spk.to_owned()));
+      assert_eq! (from_script (script.unwrap(), network), Some(addr.to_owned()));
+    }
+    /* BIP350 gives these two in uppercase, which is allowed too */
+    assert_eq! (to_script ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", Mainnet),
+                to_script ("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Mainnet));
+    assert_eq! (to_script ("BC1SW50QGDZ25J", Mainnet), to_script ("bc1sw50qgdz25j", Mainnet));
+  }
+
+  /* Invalid segwit addresses from BIP350 */
+  #[test]
+  fn segwit_invalid_vectors() {
+    let vectors = [
+      /* invalid human-readable part */
+      "tc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq5zuyut",
+      /* bech32 checksum on v1+, and bech32m on v0 */
+      "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+      "tb1z0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqglt7rf",
+      "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL",
+      "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+      "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq24jc47",
+      /* invalid character */
+      "bc1p38j9r5y49hruaue7wxjce0updqjuyyx0kh56v8s25huc6995vvpql3jow4",
+      /* invalid version */
+      "BC130XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ7ZWS8R",
+      /* invalid program lengths */
+      "bc1pw5dgrnzv",
+      "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v8n0nx0muaewav253zgeav",
+      "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+      /* mixed case */
+      "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq47Zagq",
+      /* bad padding */
+      "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v07qwwzcrf",
+      "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vpggkg4j",
+      /* empty data section */
+      "bc1gmk9yu"
+    ];
+    for &addr in vectors.iter() {
+      assert_eq! (to_script (addr, Mainnet), None);
+      assert_eq! (to_script (addr, Testnet), None);
+    }
+  }
+
+  /* Every address we make must decode back to its scriptPubKey; what
+   * has no address is shown as hex */
+  #[test]
+  fn round_trip() {
+    let scripts = [
+      "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+      "5210751e76e8199196d454941c45d1b3a323",
+      /* v0 programs which are neither 20 nor 32 bytes */
+      "0010751e76e8199196d454941c45d1b3a323",
+      "0018751e76e8199196d454941c45d1b3a323f1433bd6751e76e8"
+    ];
+    for &spk in scripts.iter() {
+      let script = hex_string_to_u8 (spk).unwrap();
+      match from_script (script, Mainnet) {
+        Some(addr) => { assert_eq! (to_script (addr, Mainnet), Some(script.clone())); }
+        None => { assert_eq! (script_to_str (script, Mainnet), spk.to_owned()); }
+      }
+    }
+    assert_eq! (from_script (hex_string_to_u8 ("0010751e76e8199196d454941c45d1b3a323").unwrap(), Mainnet), None);
+  }
+}
+
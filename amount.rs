@@ -0,0 +1,102 @@
+
+use std::to_str::ToStr;
+
+/* Satoshis per bitcoin, and the most that can ever exist */
+pub static COIN: u64 = 100000000;
+pub static MAX_MONEY: u64 = 21000000 * COIN;
+
+/**
+ * An amount of bitcoin, in satoshis. Amounts can only be made with
+ * `from_sat` or the checked arithmetic below, so never exceed MAX_MONEY
+ * (except for the one made by `sighash_single_blank`).
+ */
+#[deriving(Eq, Ord, Clone, IterBytes)]
+pub struct Amount {
+  priv sat: u64
+}
+
+impl Amount {
+  /** Zero satoshis */
+  pub fn zero () -> Amount {
+    Amount { sat: 0 }
+  }
+
+  /** An amount of satoshis, or None if it is more than MAX_MONEY */
+  pub fn from_sat (sat: u64) -> Option<Amount> {
+    if sat > MAX_MONEY { None } else { Some(Amount { sat: sat }) }
+  }
+
+  /**
+   * The value -1, which is no valid amount, but is what bitcoind gives the
+   * blanked-out outputs of a legacy SIGHASH_SINGLE signature hash. Don't
+   * use it for anything else.
+   */
+  pub fn sighash_single_blank () -> Amount {
+    Amount { sat: 0xffffffffffffffff }
+  }
+
+  /** The number of satoshis */
+  pub fn to_sat (&self) -> u64 {
+    self.sat
+  }
+
+  /** Sum of two amounts, or None if it is more than MAX_MONEY */
+  pub fn checked_add (&self, other: Amount) -> Option<Amount> {
+    /* Both sides are at most MAX_MONEY, so this can't wrap */
+    Amount::from_sat (self.to_sat() + other.to_sat())
+  }
+
+  /** Difference of two amounts, or None if it would be negative */
+  pub fn checked_sub (&self, other: Amount) -> Option<Amount> {
+    if other.to_sat() > self.to_sat() {
+      None
+    } else {
+      Some(Amount { sat: self.to_sat() - other.to_sat() })
+    }
+  }
+}
+
+/**
+ * Displays an amount in bitcoins, exactly, without trailing zeros
+ */
+impl ToStr for Amount {
+  fn to_str(&self) -> ~str
+  {
+    let whole = self.to_sat() / COIN;
+    let mut frac = format! ("{:08u}", self.to_sat() % COIN);
+    while frac.len() > 0 && frac.ends_with ("0") {
+      frac.pop_char();
+    }
+    if frac.len() == 0 {
+      format! ("{:u}", whole)
+    } else {
+      format! ("{:u}.{:s}", whole, frac)
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use amount::{Amount, MAX_MONEY};
+
+  #[test]
+  fn formatting() {
+    assert_eq! (Amount::from_sat (5380000).unwrap().to_str(), ~"0.0538");
+    assert_eq! (Amount::from_sat (100000000).unwrap().to_str(), ~"1");
+    assert_eq! (Amount::from_sat (1).unwrap().to_str(), ~"0.00000001");
+    assert_eq! (Amount::zero().to_str(), ~"0");
+    assert_eq! (Amount::from_sat (MAX_MONEY).unwrap().to_str(), ~"21000000");
+  }
+
+  #[test]
+  fn checked_arithmetic() {
+    let max = Amount::from_sat (MAX_MONEY).unwrap();
+    let one = Amount::from_sat (1).unwrap();
+    assert_eq! (Amount::from_sat (MAX_MONEY + 1), None);
+    assert_eq! (max.checked_add (one), None);
+    assert_eq! (max.checked_sub (one), Amount::from_sat (MAX_MONEY - 1));
+    assert_eq! (one.checked_sub (max), None);
+  }
+}
+
@@ -0,0 +1,405 @@
+
+use std::cmp;
+use std::vec;
+
+use address;
+use hash::Hashable;
+use script;
+use transaction::Transaction;
+use util;
+
+/* Past this many inputs or outputs, enumerating partitions takes too long */
+pub static MAX_INPUTS: uint = 12;
+pub static MAX_OUTPUTS: uint = 12;
+/* We stop counting after this many interpretations */
+pub static MAX_INTERPRETATIONS: uint = 1000000;
+/* and only list this many of them */
+static MAX_LISTED: uint = 16;
+
+/**
+ * The ways a transaction could have been put together, Boltzmann-style.
+ * An interpretation splits the inputs and outputs into groups, each of
+ * which could have been a separate transaction: its inputs pay for its
+ * outputs, with anything left over going to the fee.
+ */
+pub struct Analysis {
+  /* Number of interpretations found */
+  interpretations: uint,
+  /* Whether that is all of them, rather than where we stopped counting */
+  complete: bool,
+  /* For each input and output, how many interpretations put them together */
+  links: ~[~[uint]],
+  /* The first few interpretations, as lists of (input mask, output mask) */
+  listed: ~[~[(uint, uint)]]
+}
+
+/* State of the search for interpretations */
+struct Search {
+  in_sums: ~[u64],
+  out_sums: ~[u64],
+  groups: ~[(uint, uint)],
+  analysis: Analysis
+}
+
+/** The sum of `values` over every subset, indexed by bitmask */
+fn subset_sums (values: &[u64]) -> ~[u64]
+{
+  let mut rv = vec::from_elem (1 << values.len(), 0u64);
+  for mask in range (1u, 1 << values.len()) {
+    /* Add the lowest value in the mask to the sum of the others */
+    let low = mask & !(mask - 1);
+    let index = range (0, values.len()).find (|&i| 1 << i == low).unwrap();
+    rv[mask] = rv[mask & !low] + values[index];
+  }
+  rv
+}
+
+impl Search {
+  /** Record the interpretation made by the current groups */
+  fn record (&mut self) {
+    let analysis = &mut self.analysis;
+    for &(ins, outs) in self.groups.iter() {
+      for i in range (0, analysis.links.len()) {
+        if ins & (1 << i) == 0 { continue; }
+        for o in range (0, analysis.links[i].len()) {
+          if outs & (1 << o) != 0 {
+            analysis.links[i][o] += 1;
+          }
+        }
+      }
+    }
+    if analysis.listed.len() < MAX_LISTED {
+      analysis.listed.push (self.groups.clone());
+    }
+    analysis.interpretations += 1;
+  }
+
+  /**
+   * Find every way to split the inputs and outputs left into groups. So
+   * that each split is found once, the next group is the one holding the
+   * lowest input left.
+   */
+  fn search (&mut self, ins_left: uint, outs_left: uint, fee_left: u64) {
+    if !self.analysis.complete { return; }
+    if ins_left == 0 || outs_left == 0 {
+      /* Inputs paying only fee, or outputs paid by nothing, don't count */
+      if ins_left == 0 && outs_left == 0 {
+        if self.analysis.interpretations == MAX_INTERPRETATIONS {
+          self.analysis.complete = false;
+        } else {
+          self.record();
+        }
+      }
+      return;
+    }
+
+    let lowest = ins_left & !(ins_left - 1);
+    let others = ins_left & !lowest;
+    let mut sub = others;
+    loop {
+      let ins = lowest | sub;
+      let mut outs = outs_left;
+      while outs != 0 {
+        let in_sum = self.in_sums[ins];
+        let out_sum = self.out_sums[outs];
+        if in_sum >= out_sum && in_sum - out_sum <= fee_left {
+          self.groups.push ((ins, outs));
+          self.search (ins_left & !ins, outs_left & !outs, fee_left - (in_sum - out_sum));
+          self.groups.pop();
+        }
+        outs = (outs - 1) & outs_left;
+      }
+      if sub == 0 { break; }
+      sub = (sub - 1) & others;
+    }
+  }
+}
+
+/**
+ * Find every interpretation of a transaction with the given input and
+ * output values, or None if there are too many inputs or outputs, or the
+ * outputs are worth more than the inputs
+ */
+pub fn analyze (inputs: &[u64], outputs: &[u64]) -> Option<Analysis>
+{
+  if inputs.len() == 0 || outputs.len() == 0 { return None; }
+  if inputs.len() > MAX_INPUTS || outputs.len() > MAX_OUTPUTS { return None; }
+  let in_sums = subset_sums (inputs);
+  let out_sums = subset_sums (outputs);
+  let total_in = in_sums[in_sums.len() - 1];
+  let total_out = out_sums[out_sums.len() - 1];
+  if total_out > total_in { return None; }
+
+  let mut search = Search {
+    in_sums: in_sums,
+    out_sums: out_sums,
+    groups: ~[],
+    analysis: Analysis {
+      interpretations: 0,
+      complete: true,
+      links: vec::from_elem (inputs.len(), vec::from_elem (outputs.len(), 0u)),
+      listed: ~[]
+    }
+  };
+  search.search ((1 << inputs.len()) - 1, (1 << outputs.len()) - 1, total_in - total_out);
+  Some(search.analysis)
+}
+
+impl Analysis {
+  /**
+   * Entropy in bits: the log of the number of interpretations, which is
+   * how uncertain an observer is about which one is true
+   */
+  pub fn entropy (&self) -> f64 {
+    (self.interpretations as f64).log2()
+  }
+
+  /**
+   * Whether every interpretation links input `i` and output `o`, so that
+   * anyone can tell they belong to the same participant
+   */
+  pub fn is_deterministic (&self, i: uint, o: uint) -> bool {
+    self.complete && self.links[i][o] == self.interpretations
+  }
+
+  /** The inputs which output `o` could have been paid by */
+  pub fn possible_sources (&self, o: uint) -> ~[uint] {
+    range (0, self.links.len()).filter (|&i| self.links[i][o] > 0).collect()
+  }
+}
+
+/** Format a bitmask as a list of indices */
+fn mask_to_str (mask: uint) -> ~str
+{
+  let indices: ~[~str] = range (0u, cmp::max (MAX_INPUTS, MAX_OUTPUTS)).filter (|&i| mask & (1 << i) != 0)
+                                                                       .map (|i| i.to_str()).collect();
+  format! ("\\{{:s}\\}", indices.connect (","))
+}
+
+/**
+ * Lines of a privacy report on a merged transaction: its interpretations,
+ * their entropy, the links anyone can be sure of, and for each output
+ * whose value is shared with others, how many inputs it could really have
+ * come from. We need the value of every input for this.
+ */
+pub fn report (tx: &Transaction, network: address::Network) -> ~[~str]
+{
+  let mut rv: ~[~str] = ~[];
+  let inputs: ~[u64] = tx.input.iter().filter_map (|txin| txin.prev_output.as_ref().map (|out| out.nValue.to_sat())).collect();
+  if inputs.len() < tx.input.len() {
+    /* Don't nag people who aren't giving input values at all */
+    if inputs.len() > 0 {
+      rv.push (~"warn: Some input values are unknown, so there is no privacy analysis.");
+    }
+    return rv;
+  }
+  let outputs: ~[u64] = tx.output.iter().map (|txout| txout.nValue.to_sat()).collect();
+
+  let analysis = match analyze (inputs, outputs) {
+    Some(a) => a,
+    None => {
+      rv.push (format! ("warn: No privacy analysis for more than {:u} inputs or {:u} outputs.",
+        MAX_INPUTS, MAX_OUTPUTS));
+      return rv;
+    }
+  };
+
+  /* If we stopped counting, there may be more of everything */
+  let bound = if analysis.complete { "" } else { "at least " };
+  rv.push (format! ("interpretations: {:s}{:u}", bound, analysis.interpretations));
+  rv.push (format! ("entropy: {:s}{:.2f} bits", bound, analysis.entropy()));
+  for (n, groups) in analysis.listed.iter().enumerate() {
+    let parts: ~[~str] = groups.iter().map (|&(ins, outs)| {
+      format! ("{:s}->{:s}", mask_to_str (ins), mask_to_str (outs))
+    }).collect();
+    rv.push (format! ("interpretation {:u}: {:s}", n + 1, parts.connect (" ")));
+  }
+
+  for (i, txin) in tx.input.iter().enumerate() {
+    for o in range (0, tx.output.len()) {
+      if analysis.is_deterministic (i, o) {
+        rv.push (format! ("warn: Input {:s}:{:u} is always linked to output {:u} ({:s}).",
+          util::u8_to_hex_string (txin.prev_hash), txin.prev_index, o,
+          address::script_to_str (tx.output[o].scriptPubKey, network)));
+      }
+    }
+  }
+
+  for (o, txout) in tx.output.iter().enumerate() {
+    let same_value = tx.output.iter().filter (|other| other.nValue == txout.nValue).count();
+    if same_value < 2 { continue; }
+    rv.push (format! ("anonset {:u}: {:u} outputs of {:s}, from {:s}{:u} of {:u} inputs",
+      o, same_value, txout.nValue.to_str(), bound, analysis.possible_sources (o).len(), tx.input.len()));
+  }
+  rv
+}
+
+/* Values which are a multiple of this (a mBTC) look like payments */
+static ROUND_AMOUNT: u64 = 100000;
+
+/** Whether an amount of satoshis is a round number */
+pub fn is_round (sat: u64) -> bool
+{
+  sat > 0 && sat % ROUND_AMOUNT == 0
+}
+
+/**
+ * Warnings about outputs which chain analysis would easily attribute to
+ * the submission they came from, so the coordinator can bounce it before
+ * the merged transaction goes out for signing. An output whose value is
+ * shared with others in `merged` is mixed; any other output is change,
+ * which is flagged if its value is unique (it always is, except for
+ * duplicates) or round. We also flag outputs of a different script type
+ * from all their submission's inputs, and outputs paying a scriptPubKey
+ * that some input spends from.
+ */
+pub fn heuristics (txlist: &[Transaction], merged: &Transaction, network: address::Network) -> ~[~str]
+{
+  let mut rv: ~[~str] = ~[];
+  for (n, tx) in txlist.iter().enumerate() {
+    let in_types: ~[Option<script::ScriptType>] = tx.input.iter().map (|txin| {
+      txin.prev_output.as_ref().map (|out| out.script_type())
+    }).collect();
+
+    for txout in tx.output.iter() {
+      let addr = address::script_to_str (txout.scriptPubKey, network);
+      let same_value = merged.output.iter().filter (|other| other.nValue == txout.nValue).count();
+      let mut flags: ~[~str] = ~[];
+
+      if same_value < 2 {
+        flags.push (~"its value is unique");
+        if is_round (txout.nValue.to_sat()) {
+          flags.push (~"it is a round number, so looks like a payment");
+        }
+      }
+      if in_types.len() > 0 && in_types.iter().all (|ty| ty.is_some() && *ty != Some(txout.script_type())) {
+        flags.push (format! ("it is {:s}, unlike the inputs", txout.script_type().name()));
+      }
+      if merged.input.iter().any (|txin| match txin.prev_output {
+        Some(ref out) => out.scriptPubKey == txout.scriptPubKey,
+        None => false
+      }) {
+        flags.push (~"its address is also spent from");
+      }
+
+      if flags.len() > 0 {
+        rv.push (format! ("warn: Submission {:u} (tx {:s}) output to {:s} of {:s} stands out: {:s}.",
+          n + 1, util::u8_to_hex_string (tx.to_hash()), addr, txout.nValue.to_str(), flags.connect ("; ")));
+      }
+    }
+  }
+  rv
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::vec;
+
+  use address;
+  use amount::Amount;
+  use analysis::{MAX_INTERPRETATIONS, analyze, heuristics, is_round, report};
+  use transaction::{Transaction, TxIn, TxOut};
+  use util::hex_string_to_u8;
+
+  fn txout (sat: u64, spk: &str) -> TxOut {
+    TxOut { nValue: Amount::from_sat (sat).unwrap(), scriptPubKey: hex_string_to_u8 (spk).unwrap() }
+  }
+
+  /** An input spending `out`, from a transaction whose hash is all `fill` */
+  fn txin (fill: u8, out: TxOut) -> TxIn {
+    TxIn { prev_hash: vec::from_elem (32, fill), prev_index: 0, scriptSig: ~[], nSequence: 0xffffffff,
+           nHashType: 0, witness: ~[], prev_output: Some(out) }
+  }
+
+  #[test]
+  fn equal_values() {
+    /* Both inputs together, or each to either output */
+    let a = analyze ([100, 100], [100, 100]).unwrap();
+    assert_eq! (a.interpretations, 3);
+    assert! (a.complete);
+    assert_eq! (a.links, ~[~[2, 2], ~[2, 2]]);
+    assert! (!a.is_deterministic (0, 0));
+    assert_eq! (a.possible_sources (1), ~[0, 1]);
+  }
+
+  #[test]
+  fn deterministic_links() {
+    /* Only the first input can pay for the first output, so whether or
+     * not the two were one transaction, each input pays its own output */
+    let a = analyze ([300, 100], [250, 100]).unwrap();
+    assert_eq! (a.interpretations, 2);
+    assert_eq! (a.links, ~[~[2, 1], ~[1, 2]]);
+    assert! (a.is_deterministic (0, 0));
+    assert! (a.is_deterministic (1, 1));
+    assert! (!a.is_deterministic (0, 1));
+
+    let a = analyze ([100], [60, 30]).unwrap();
+    assert_eq! (a.interpretations, 1);
+    assert! (a.is_deterministic (0, 1));
+  }
+
+  #[test]
+  fn round_numbers() {
+    assert! (is_round (100000));
+    assert! (is_round (5000000));
+    assert! (!is_round (5380001));
+    assert! (!is_round (0));
+  }
+
+  #[test]
+  fn standing_out() {
+    let spent1 = "0014aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    let spent2 = "0014dddddddddddddddddddddddddddddddddddddddd";
+    let txlist = ~[
+      Transaction { nVersion: 1, nLockTime: 0,
+                    input: ~[txin (1, txout (6300000, spent1))],
+                    output: ~[txout (5000000, "0014bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"),
+                              /* change of another script type */
+                              txout (1234567, "76a914cccccccccccccccccccccccccccccccccccccccc88ac")] },
+      Transaction { nVersion: 1, nLockTime: 0,
+                    input: ~[txin (2, txout (5800000, spent2))],
+                    output: ~[txout (5000000, "0014eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee"),
+                              /* round change, back to the other submission's address */
+                              txout (700000, spent1)] }
+    ];
+    let mut merged = Transaction { nVersion: 1, nLockTime: 0, input: ~[], output: ~[] };
+    for tx in txlist.iter() {
+      merged.input.push_all (tx.input);
+      merged.output.push_all (tx.output);
+    }
+
+    /* The equal outputs are mixed, and say nothing */
+    let rv = heuristics (txlist, &merged, address::Mainnet);
+    assert_eq! (rv.len(), 2);
+    assert! (rv[0].starts_with ("warn: Submission 1 "));
+    assert! (rv[0].ends_with (" of 0.01234567 stands out: its value is unique; it is p2pkh, unlike the inputs."));
+    assert! (rv[1].starts_with ("warn: Submission 2 "));
+    assert! (rv[1].ends_with (" of 0.007 stands out: its value is unique; it is a round number, so looks like a payment; its address is also spent from."));
+  }
+
+  #[test]
+  fn truncated() {
+    /* Eight equal inputs and outputs can be split nearly ten million ways */
+    let a = analyze ([100, ..8], [100, ..8]).unwrap();
+    assert! (!a.complete);
+    assert_eq! (a.interpretations, MAX_INTERPRETATIONS);
+
+    let mut tx = Transaction { nVersion: 1, nLockTime: 0, input: ~[], output: ~[] };
+    for n in range (0u8, 8) {
+      tx.input.push (txin (n, txout (100, "0014aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")));
+      tx.output.push (txout (100, "0014bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"));
+    }
+    let rv = report (&tx, address::Mainnet);
+    assert_eq! (rv[0], ~"interpretations: at least 1000000");
+    assert_eq! (rv[1], ~"entropy: at least 19.93 bits");
+    assert_eq! (rv[rv.len() - 1], ~"anonset 7: 8 outputs of 0.000001, from at least 8 of 8 inputs");
+  }
+
+  #[test]
+  fn impossible() {
+    assert! (analyze ([100], [60, 50]).is_none());
+    assert! (analyze ([1, ..13], [1]).is_none());
+  }
+}
+
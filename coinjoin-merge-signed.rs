@@ -1,10 +1,18 @@
 
+use std::os;
+
 use hash::Hashable;
 
 /* Modules */
+mod address;
+mod amount;
+mod analysis;
 mod decoder;
 mod hash;
 mod merge_signed;
+mod multisig;
+mod script;
+mod secp256k1;
 mod transaction;
 mod util;
 
@@ -16,22 +24,100 @@ fn main()
   println ("Welcome to coinjoin-merger-signed. Enter each partially-signed raw transaction");
   println ("on a separate line, followed by a blank line or EOF to finish.");
 
+  let mut mode = transaction::Strict;
+  let mut network = address::Mainnet;
+  let mut prevouts: ~[(~[u8], u32, transaction::TxOut)] = ~[];
+  for arg in os::args().iter().skip (1) {
+    if arg.as_slice() == "--lenient" {
+      mode = transaction::Lenient;
+    } else if arg.starts_with ("--network=") {
+      match address::network_from_str (arg.slice_from (10)) {
+        Some(n) => { network = n; }
+        None => {
+          println (format! ("err: Unknown network {:s}", arg.slice_from (10)));
+          return;
+        }
+      }
+    } else if arg.starts_with ("--prevouts=") {
+      let path = arg.slice_from (11);
+      let lines = match util::read_lines (path) {
+        Some(l) => l,
+        None => {
+          println (format! ("err: Could not read prevouts file {:s}", path));
+          return;
+        }
+      };
+      for (n, line) in lines.iter().enumerate() {
+        if line.trim().len() == 0 { continue; }
+        match transaction::prevout_from_str (*line) {
+          Some(p) => { prevouts.push (p); }
+          None => {
+            println (format! ("err: Bad prevout on line {:u} of {:s}", n + 1, path));
+            return;
+          }
+        }
+      }
+    } else {
+      println (format! ("err: Unknown option {:s}", *arg));
+      return;
+    }
+  }
+
   let mut transactions: ~[transaction::Transaction] = ~[];
 
+  let mut line_no = 1u;
   let mut next_ln = util::read_hex();
   while next_ln.len() > 0 {
-    match transaction::from_hex (next_ln) {
-      Some(t) => { transactions.push (t); }
-      None => { println ("err: Failed to decode transaction."); }
+    match transaction::from_hex_with_mode (next_ln, mode) {
+      Ok((mut t, warnings)) => {
+        for w in warnings.iter() {
+          println (format! ("warn: Transaction on line {:u}: {:s}", line_no, w.to_str()));
+        }
+        t.set_prevouts (prevouts);
+        transactions.push (t);
+      }
+      Err(e) => {
+        println (format! ("err: Failed to decode transaction on line {:u}: {:s}", line_no, e.to_str()));
+      }
     }
+    line_no += 1;
     next_ln = util::read_hex();
   }
 
-  match merge_signed::merge_signed_transactions (transactions) {
+  match merge_signed::merge_signed_transactions (transactions, network) {
     None => { println ("err: Failed to merge transactions."); }
     Some(t) => {
-      println (format! ("mpo: {:f}", (t.most_popular_output() as f64) / 100000000f64 ));
+      println (format! ("mpo: {:s}", t.most_popular_output().to_str()));
       println (format! ("mpc: {:u}", t.most_popular_output_count()));
+      println (format! ("txid: {:s}", util::u8_to_hex_string (t.to_hash())));
+      println (format! ("wtxid: {:s}", util::u8_to_hex_string (t.wtxid())));
+      println (format! ("size: {:u}", t.total_size()));
+      println (format! ("stripped-size: {:u}", t.stripped_size()));
+      println (format! ("weight: {:u}", t.weight()));
+      println (format! ("vsize: {:u}", t.vsize()));
+      match t.fee() {
+        Some(fee) => { println (format! ("fee: {:s}", fee.to_str())); }
+        None => {}
+      }
+      /* Report the mix of output types; an output whose type is unique in
+       * the transaction is trivially linked to whoever asked for it. */
+      let counts = t.output_type_counts();
+      let mix: ~[~str] = counts.iter().map (|&(ty, n)| format! ("{:s}={:u}", ty.name(), n)).collect();
+      println (format! ("outtypes: {:s}", mix.connect (" ")));
+      for (n, output) in t.output.iter().enumerate() {
+        println (format! ("output {:u}: {:s} {:u}", n,
+          address::script_to_str (output.scriptPubKey, network), output.nValue.to_sat()));
+      }
+      if counts.len() > 1 {
+        for &(ty, n) in counts.iter() {
+          if n == 1 {
+            println (format! ("warn: Only one {:s} output; it stands out from the rest.", ty.name()));
+          }
+        }
+      }
+      for line in analysis::report (&t, network).iter() {
+        println (*line);
+      }
       println (format! ("hex: {:s}", t.to_str()));
     }
   }
@@ -1,37 +1,304 @@
 
+use std::os;
+use std::from_str::from_str;
+
 use hash::Hashable;
 
 /* Modules */
+mod address;
+mod amount;
+mod analysis;
 mod decoder;
+mod fee;
 mod hash;
 mod merge_unsigned;
+mod multisig;
+mod script;
+mod secp256k1;
+mod seed;
 mod transaction;
 mod util;
 
+/**
+ * Split an input line into the transaction, the nonce contributing to the
+ * shuffle seed if given, and any outputs it spends that the submitter told
+ * us about: `<tx hex> [nonce=<hex>] [prevout=<txid>:<vout>:<sats>:<spk hex>]...`
+ */
+fn parse_submission (line: &str) -> Result<(~[u8], Option<~[u8]>, ~[(~[u8], u32, transaction::TxOut)]), ~str>
+{
+  let words: ~[&str] = line.words().collect();
+  let tx = match util::hex_string_to_u8 (words[0]) {
+    Some(tx) => tx,
+    None => { return Err (~"transaction is not hex"); }
+  };
+  let mut nonce = None;
+  let mut prevouts = ~[];
+  for word in words.slice_from (1).iter() {
+    if word.starts_with ("nonce=") {
+      match util::hex_string_to_u8 (word.slice_from (6)) {
+        Some(n) if n.len() == 32 => { nonce = Some(n); }
+        _ => { return Err (~"nonce is not 32 bytes of hex"); }
+      }
+    } else if word.starts_with ("prevout=") {
+      let parts: ~[&str] = word.slice_from (8).split (':').collect();
+      if parts.len() != 4 {
+        return Err (format! ("bad prevout {:s}", word.slice_from (8)));
+      }
+      let desc = format! ("{:s}:{:s} {:s} {:s}", parts[0], parts[1], parts[2], parts[3]);
+      match transaction::prevout_from_str (desc) {
+        Some(p) => { prevouts.push (p); }
+        None => { return Err (format! ("bad prevout {:s}", word.slice_from (8))); }
+      }
+    } else {
+      return Err (format! ("unknown field {:s}", *word));
+    }
+  }
+  Ok((tx, nonce, prevouts))
+}
+
 /**
  * Entry point
  */
 fn main()
 {
   println ("Welcome to coinjoin-merge-unsigned. Enter each unsigned raw transaction");
-  println ("on a separate line, followed by a blank line or EOF to finish.");
+  println ("on a separate line, followed by a blank line or EOF to finish. To help");
+  println ("choose the shuffle, each line may end with nonce=<hex>.");
+
+  let mut mode = transaction::Strict;
+  let mut options = merge_unsigned::MergeOptions::new();
+  let mut published: Option<~[~[u8]]> = None;
+  let mut prevouts: ~[(~[u8], u32, transaction::TxOut)] = ~[];
+  for arg in os::args().iter().skip (1) {
+    if arg.as_slice() == "--lenient" {
+      mode = transaction::Lenient;
+    } else if arg.starts_with ("--network=") {
+      match address::network_from_str (arg.slice_from (10)) {
+        Some(n) => { options.network = n; }
+        None => {
+          println (format! ("err: Unknown network {:s}", arg.slice_from (10)));
+          return;
+        }
+      }
+    } else if arg.starts_with ("--duplicate-outputs=") {
+      match merge_unsigned::duplicate_policy_from_str (arg.slice_from (20)) {
+        Some(p) => { options.duplicate_outputs = p; }
+        None => {
+          println (format! ("err: Unknown duplicate output policy {:s}", arg.slice_from (20)));
+          return;
+        }
+      }
+    } else if arg.starts_with ("--order=") {
+      match merge_unsigned::order_policy_from_str (arg.slice_from (8)) {
+        Some(o) => { options.order = o; }
+        None => {
+          println (format! ("err: Unknown order {:s}", arg.slice_from (8)));
+          return;
+        }
+      }
+    } else if arg.starts_with ("--denomination=") {
+      match from_str::<u64> (arg.slice_from (15)).and_then (|sat| amount::Amount::from_sat (sat)) {
+        Some(value) if value.to_sat() > 0 => { options.denominations.push (value); }
+        _ => {
+          println (format! ("err: Bad denomination {:s}; it should be in satoshis", arg.slice_from (15)));
+          return;
+        }
+      }
+    } else if arg.starts_with ("--fee-tolerance=") {
+      match from_str::<uint> (arg.slice_from (16)) {
+        Some(pct) if pct <= 100 => { options.fee_tolerance = pct; }
+        _ => {
+          println (format! ("err: Bad fee tolerance {:s}; it should be a percentage", arg.slice_from (16)));
+          return;
+        }
+      }
+    } else if arg.as_slice() == "--strict-fees" {
+      options.strict_fees = true;
+    } else if arg.starts_with ("--prevouts=") {
+      let path = arg.slice_from (11);
+      let lines = match util::read_lines (path) {
+        Some(l) => l,
+        None => {
+          println (format! ("err: Could not read prevouts file {:s}", path));
+          return;
+        }
+      };
+      for (n, line) in lines.iter().enumerate() {
+        if line.trim().len() == 0 { continue; }
+        match transaction::prevout_from_str (*line) {
+          Some(p) => { prevouts.push (p); }
+          None => {
+            println (format! ("err: Bad prevout on line {:u} of {:s}", n + 1, path));
+            return;
+          }
+        }
+      }
+    } else if arg.starts_with ("--commitments=") {
+      let path = arg.slice_from (14);
+      let lines = match util::read_lines (path) {
+        Some(l) => l,
+        None => {
+          println (format! ("err: Could not read commitments file {:s}", path));
+          return;
+        }
+      };
+      let mut list: ~[~[u8]] = ~[];
+      for (n, line) in lines.iter().enumerate() {
+        if line.trim().len() == 0 { continue; }
+        match util::hex_string_to_u8 (line.trim()) {
+          Some(c) if c.len() == 32 => { list.push (c); }
+          _ => {
+            println (format! ("err: Bad commitment on line {:u} of {:s}", n + 1, path));
+            return;
+          }
+        }
+      }
+      published = Some(list);
+    } else {
+      println (format! ("err: Unknown option {:s}", *arg));
+      return;
+    }
+  }
 
   let mut transactions: ~[transaction::Transaction] = ~[];
+  let mut nonces: ~[~[u8]] = ~[];
+
+  let mut line_no = 1u;
+  loop {
+    let line = match util::read_line() {
+      Some(l) => l,
+      None => { break; }
+    };
+    if line.trim().len() == 0 { break; }
 
-  let mut next_ln = util::read_hex();
-  while next_ln.len() > 0 {
-    match transaction::from_hex (next_ln) {
-      Some(t) => { transactions.push (t); }
-      None => { println ("err: Failed to decode transaction."); }
+    match parse_submission (line) {
+      Err(e) => {
+        println (format! ("err: Failed to read line {:u}: {:s}", line_no, e));
+      }
+      Ok((hex, nonce, embedded)) => {
+        match transaction::from_hex_with_mode (hex, mode) {
+          Ok((mut t, warnings)) => {
+            for w in warnings.iter() {
+              println (format! ("warn: Transaction on line {:u}: {:s}", line_no, w.to_str()));
+            }
+            /* The submitter's own word on what their inputs are worth is
+             * overridden by the file, which we trust more */
+            t.set_prevouts (embedded);
+            t.set_prevouts (prevouts);
+            transactions.push (t);
+            match nonce {
+              Some(n) => { nonces.push (n); }
+              None => {}
+            }
+          }
+          Err(e) => {
+            println (format! ("err: Failed to decode transaction on line {:u}: {:s}", line_no, e.to_str()));
+          }
+        }
+      }
     }
-    next_ln = util::read_hex();
+    line_no += 1;
+  }
+
+  /* Derive the shuffle seed from the revealed nonces, checking them
+   * against the published commitments if we have them */
+  let mut commitments: ~[~[u8]] = nonces.iter().map (|n| seed::commitment (*n)).collect();
+  commitments.sort_by (|c1, c2| c1.cmp (c2));
+  match published {
+    Some(ref list) => {
+      for c in commitments.iter() {
+        if !list.contains (c) {
+          println (format! ("err: Nonce with commitment {:s} was not published in advance.",
+            util::u8_to_hex_string (*c)));
+          return;
+        }
+      }
+      /* Someone who holds back their nonce after seeing everyone else's
+       * could choose between two shuffles, by revealing it or not */
+      let mut unrevealed = false;
+      for c in list.iter() {
+        if !commitments.contains (c) {
+          println (format! ("err: Commitment {:s} was never revealed. Refusing to merge.",
+            util::u8_to_hex_string (*c)));
+          unrevealed = true;
+        }
+      }
+      if unrevealed {
+        return;
+      }
+    }
+    None => {
+      /* Then nothing stops whoever sees the nonces first (us) from
+       * grinding one of their own to pick the shuffle */
+      if nonces.len() > 0 {
+        println ("warn: Nonces were given without --commitments, so they weren't committed to in advance and the shuffle can't be trusted.");
+      }
+    }
+  }
+  if nonces.len() > 0 && nonces.len() < transactions.len() {
+    println (format! ("warn: Only {:u} of {:u} submissions gave a nonce; the others have no say in the shuffle.",
+      nonces.len(), transactions.len()));
+  }
+  if nonces.len() > 0 {
+    options.seed = seed::from_nonces (nonces);
+  } else {
+    println ("warn: No nonces were given, so the shuffle seed is our own and can't be checked.");
   }
 
-  match merge_unsigned::merge_unsigned_transactions (transactions) {
+  match merge_unsigned::merge_unsigned_transactions (transactions, &options) {
     None => { println ("err: Failed to merge transactions."); }
     Some(t) => {
-      println (format! ("mpo: {:f}", (t.most_popular_output() as f64) / 100000000f64 ));
+      println (format! ("mpo: {:s}", t.most_popular_output().to_str()));
       println (format! ("mpc: {:u}", t.most_popular_output_count()));
+      println (format! ("txid: {:s}", util::u8_to_hex_string (t.to_hash())));
+      println (format! ("wtxid: {:s}", util::u8_to_hex_string (t.wtxid())));
+      println (format! ("size: {:u}", t.total_size()));
+      println (format! ("stripped-size: {:u}", t.stripped_size()));
+      println (format! ("weight: {:u}", t.weight()));
+      println (format! ("vsize: {:u}", t.vsize()));
+      /* What each submission contributes to the fee, where we know its
+       * input values */
+      for (n, tx) in transactions.iter().enumerate() {
+        match tx.fee() {
+          Some(fee) => { println (format! ("fee {:u}: {:s}", n + 1, fee.to_str())); }
+          None => {}
+        }
+      }
+      match t.fee() {
+        Some(fee) => { println (format! ("fee: {:s}", fee.to_str())); }
+        None if prevouts.len() > 0 || transactions.iter().any (|tx| tx.input_value().is_some()) => {
+          println ("warn: Some input values are unknown, so the total fee is too.");
+        }
+        None => {}
+      }
+      /* Report the mix of output types; an output whose type is unique in
+       * the transaction is trivially linked to whoever asked for it. */
+      let counts = t.output_type_counts();
+      let mix: ~[~str] = counts.iter().map (|&(ty, n)| format! ("{:s}={:u}", ty.name(), n)).collect();
+      println (format! ("outtypes: {:s}", mix.connect (" ")));
+      for (n, output) in t.output.iter().enumerate() {
+        println (format! ("output {:u}: {:s} {:u}", n,
+          address::script_to_str (output.scriptPubKey, options.network), output.nValue.to_sat()));
+      }
+      if counts.len() > 1 {
+        for &(ty, n) in counts.iter() {
+          if n == 1 {
+            println (format! ("warn: Only one {:s} output; it stands out from the rest.", ty.name()));
+          }
+        }
+      }
+      for line in analysis::report (&t, options.network).iter() {
+        println (*line);
+      }
+      for line in analysis::heuristics (transactions, &t, options.network).iter() {
+        println (*line);
+      }
+      /* A seed of our own can't be checked by anyone, so don't show one */
+      if nonces.len() > 0 {
+        println (format! ("seed: {:s}", util::u8_to_hex_string (options.seed)));
+        let shown: ~[~str] = commitments.iter().map (|c| util::u8_to_hex_string (*c)).collect();
+        println (format! ("commitments: {:s}", shown.connect (" ")));
+      }
       println (format! ("hex: {:s}", t.to_str()));
     }
   }
@@ -11,21 +11,23 @@ pub enum TokenType {
 pub enum Token {
   Integer(u64),
   String(~[u8]),
-  Invalid
+  /* A VarInt which was not minimally encoded, along with its value */
+  NonMinimal(u64),
+  /* Ran out of input: (bytes wanted, bytes available) */
+  Truncated(u64, u64)
 }
 
 fn decode_integer (iter: &mut VecIterator<u8>, width: int) -> Token {
-  let mut success = true;
+  let mut got: u64 = 0;
   let mut rv: u64 = 0;
 
   for i in range (0, width) {
     match iter.next() {
-      None => { success = false; break }
-      Some(&ch) => { rv += (ch as u64) << 8 * i; }
+      None => { return Truncated (width as u64, got) }
+      Some(&ch) => { rv += (ch as u64) << 8 * i; got += 1; }
     }
   }
-  if success { Integer (rv) }
-  else       { Invalid }
+  Integer (rv)
 }
 
 
@@ -38,28 +40,32 @@ pub fn decode_token (iter: &mut VecIterator<u8>, expected_token: TokenType) -> T
     VarInt => {
       match iter.next() {
         Some(&ch) => {
-          match ch {
-            0xff => decode_integer (iter, 8),
-            0xfe => decode_integer (iter, 4),
-            0xfd => decode_integer (iter, 2),
-            n => { Integer(n as u64) }
+          /* Each width must be needed, i.e. the value must not have
+           * fit in the next-smaller encoding. */
+          let (token, min) = match ch {
+            0xff => (decode_integer (iter, 8), 0x100000000),
+            0xfe => (decode_integer (iter, 4), 0x10000),
+            0xfd => (decode_integer (iter, 2), 0xfd),
+            n => { return Integer(n as u64) }
+          };
+          match token {
+            Integer(n) if n < min => NonMinimal(n),
+            t => t
           }
         }
-        None => Invalid
+        None => Truncated (1, 0)
       }
     }
     /* Strings */
     Bytestring(len) => {
-      let mut success = true;
       let mut rv: ~[u8] = ~[];
       for _ in range (0, len) {
         match iter.next() {
-          None => { success = false; break }
+          None => { return Truncated (len, rv.len() as u64) }
           Some(&ch) => { rv.push (ch); }
         }
       }
-      if success { String (rv) }
-      else       { Invalid }
+      String (rv)
     }
   }
 }
@@ -0,0 +1,256 @@
+
+use hash;
+use hash::Hashable;
+use multisig;
+use script;
+use transaction::{Transaction, TxIn, TxOut};
+use util;
+
+/* Size we assume for a signature, with its hash type byte, on an input
+ * which has not been signed yet */
+static SIGNATURE_SIZE: uint = 72;
+
+/* Weight of the version, locktime and segwit marker and flag, which is
+ * shared between all submissions (we assume there is a witness, as for
+ * nearly every coinjoin now) */
+static FIXED_WEIGHT: uint = 4 * (4 + 4) + 2;
+
+fn vi_size (n: uint) -> uint
+{
+  hash::push_vi_le (~[], n as u64).len()
+}
+
+/** Weight of the outpoint, sequence and a scriptSig of length `len` */
+fn base_input_weight (len: uint) -> uint
+{
+  4 * (36 + vi_size (len) + len + 4)
+}
+
+/**
+ * Estimated weight of an input once it is signed. Most inputs are judged by
+ * the type of output they spend, with the usual estimates (148 vbytes for
+ * P2PKH, 91 for P2SH, which we assume wraps P2WPKH, 68 for P2WPKH and 57.5
+ * for P2TR); multisig inputs by their script. Anything else is taken at its
+ * current size, which is too small if it isn't signed yet.
+ */
+pub fn input_weight (txin: &TxIn) -> uint
+{
+  match multisig::parse_input (txin) {
+    Some(ms) => {
+      let sigs = ms.m * (1 + SIGNATURE_SIZE);
+      let script = vi_size (ms.script.len()) + ms.script.len();
+      return if !ms.segwit {
+        /* OP_0, the signatures, and a push of the redeem script */
+        let len = 1 + sigs + script::serialize ([script::push_data (ms.script.clone())]).len();
+        base_input_weight (len)
+      } else {
+        /* the dummy element, the signatures and the witness script, and
+         * for nested P2SH-P2WSH a push of the witness program */
+        base_input_weight (txin.scriptSig.len()) + vi_size (ms.m + 2) + 1 + sigs + script
+      };
+    }
+    None => {}
+  }
+
+  match txin.prev_output {
+    Some(ref out) => match out.script_type() {
+      script::PayToPubkeyHash => { return 592; }
+      script::PayToScriptHash => { return 364; }
+      script::PayToWitnessPubkeyHash => { return 272; }
+      script::PayToTaproot => { return 230; }
+      _ => {}
+    },
+    None => {}
+  }
+
+  let mut witness = 0;
+  if txin.witness.len() > 0 {
+    witness = vi_size (txin.witness.len());
+    for item in txin.witness.iter() {
+      witness += vi_size (item.len()) + item.len();
+    }
+  }
+  base_input_weight (txin.scriptSig.len()) + witness
+}
+
+/** Weight of an output */
+pub fn output_weight (txout: &TxOut) -> uint
+{
+  4 * (8 + vi_size (txout.scriptPubKey.len()) + txout.scriptPubKey.len())
+}
+
+/** Estimated weight of the inputs and outputs a submission adds */
+pub fn submission_weight (tx: &Transaction) -> uint
+{
+  tx.input.iter().fold (0, |w, txin| w + input_weight (txin)) +
+    tx.output.iter().fold (0, |w, txout| w + output_weight (txout))
+}
+
+/** Format a rate in millisatoshis per vbyte as satoshis per vbyte */
+fn rate_to_str (msat_per_vb: u64) -> ~str
+{
+  format! ("{:u}.{:03u} sat/vB", msat_per_vb / 1000, msat_per_vb % 1000)
+}
+
+/**
+ * The lowest fee rate we accept, `tolerance` percent below `rate`, without
+ * overflowing for any rate
+ */
+fn tolerated_rate (rate: u64, tolerance: uint) -> u64
+{
+  rate / 100 * (100 - tolerance as u64) + rate % 100 * (100 - tolerance as u64) / 100
+}
+
+/**
+ * Check that each submission pays its share of the fee: the fee rate of
+ * the whole transaction applied to the weight of its own inputs and outputs,
+ * plus an equal part of the weight they all share. A submission may pay
+ * up to `tolerance` percent less than that. Underpayers are warned about,
+ * or if `strict`, refused. `ninputs` and `noutputs` are the sizes of the
+ * merged transaction.
+ *
+ * Returns false if we should refuse to merge.
+ */
+pub fn check_fairness (txlist: &[Transaction], ninputs: uint, noutputs: uint,
+                       tolerance: uint, strict: bool) -> bool
+{
+  /* If nobody's input values are known, the caller just didn't give any,
+   * and there is nothing to warn about */
+  let any_known = txlist.iter().any (|tx| tx.fee().is_some());
+  let mut fees: ~[u64] = ~[];
+  for (n, tx) in txlist.iter().enumerate() {
+    match tx.fee() {
+      Some(fee) => { fees.push (fee.to_sat()); }
+      None => {
+        if strict {
+          println (format! ("err: Inputs of submission {:u} (tx {:s}) have unknown values, so its fee can't be checked.",
+            n + 1, util::u8_to_hex_string (tx.to_hash())));
+          return false;
+        }
+        if any_known {
+          println (format! ("warn: Inputs of submission {:u} (tx {:s}) have unknown values, so nobody's fee can be checked.",
+            n + 1, util::u8_to_hex_string (tx.to_hash())));
+        }
+        return true;
+      }
+    }
+  }
+
+  let shared = FIXED_WEIGHT + 4 * (vi_size (ninputs) + vi_size (noutputs));
+  let weights: ~[uint] = txlist.iter().map (|tx| submission_weight (tx) + shared / txlist.len()).collect();
+  let total_weight = weights.iter().fold (0, |a, &b| a + b);
+  /* Fees are at most MAX_MONEY, so none of this can overflow */
+  let total_fee = fees.iter().fold (0, |a, &b| a + b);
+  let rate = total_fee * 4000 / (total_weight as u64);
+  let threshold = tolerated_rate (rate, tolerance);
+  println (format! ("feerate: {:s}", rate_to_str (rate)));
+
+  let mut fair = true;
+  for (n, tx) in txlist.iter().enumerate() {
+    let vbytes = ((weights[n] + 3) / 4) as u64;
+    let own_rate = fees[n] * 1000 / vbytes;
+    println (format! ("feerate {:u}: {:s}", n + 1, rate_to_str (own_rate)));
+    if own_rate < threshold {
+      let share = rate / 1000 * vbytes + rate % 1000 * vbytes / 1000;
+      println (format! ("{:s}: Submission {:u} (tx {:s}) pays {:u} sats in fees for its {:u} vbytes, less than its share of {:u}.",
+        if strict { "err" } else { "warn" },
+        n + 1, util::u8_to_hex_string (tx.to_hash()), fees[n], vbytes, share));
+      fair = false;
+    }
+  }
+  fair || !strict
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::vec;
+
+  use amount::Amount;
+  use fee::{check_fairness, input_weight, tolerated_rate};
+  use transaction;
+  use transaction::{TxIn, TxOut};
+  use util::hex_string_to_u8;
+
+  /* A 2-of-3 multisig script */
+  static MULTISIG: &'static str = "52210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f21039000d5808852f8a3a7f83736e108705c3ec0452fa056133acd668b121ca0964c53ae";
+
+  /* Two submissions of one input and one output each, the second paying
+   * no fee, as in the fee-underpaid test */
+  static FIRST: &'static str = "010000000119f486a20df2078f6992e6c09eac14b0d6b3ec6d2095a2056ff58a55eacda7560000000000ffffffff01a0175200000000001976a914b69a83e41dc486f52e9d4b4bc42e57dae2c7210288ac00000000";
+  static SECOND: &'static str = "010000000122222222222222222222222222222222222222222222222222222222222222220100000000ffffffff01a0175200000000001976a914111111111111111111111111111111111111111188ac00000000";
+  static PREVOUTS: [&'static str, ..2] = [
+    "56a7cdea558af56f05a295206decb3d6b014ac9ec0e692698f07f20da286f419:0 5390000 00144444444444444444444444444444444444444444",
+    "2222222222222222222222222222222222222222222222222222222222222222:1 5380000 00144444444444444444444444444444444444444444"
+  ];
+
+  /** An unsigned input, spending an output with the given script if known */
+  fn txin (spk: Option<&str>) -> TxIn {
+    TxIn { prev_hash: vec::from_elem (32, 1u8), prev_index: 0, scriptSig: ~[], nSequence: 0xffffffff,
+           nHashType: 0, witness: ~[],
+           prev_output: spk.map (|spk| TxOut { nValue: Amount::from_sat (100000).unwrap(),
+                                              scriptPubKey: hex_string_to_u8 (spk).unwrap() }) }
+  }
+
+  fn submissions (prevouts: bool) -> ~[transaction::Transaction] {
+    [FIRST, SECOND].iter().map (|hex| {
+      let mut tx = transaction::from_hex (hex_string_to_u8 (*hex).unwrap()).unwrap();
+      if prevouts {
+        let list: ~[(~[u8], u32, TxOut)] = PREVOUTS.iter().map (|p| transaction::prevout_from_str (*p).unwrap()).collect();
+        tx.set_prevouts (list);
+      }
+      tx
+    }).collect()
+  }
+
+  #[test]
+  fn single_key_estimates() {
+    assert_eq! (input_weight (&txin (Some("76a914b69a83e41dc486f52e9d4b4bc42e57dae2c7210288ac"))), 592);
+    assert_eq! (input_weight (&txin (Some("00144444444444444444444444444444444444444444"))), 272);
+    assert_eq! (input_weight (&txin (Some("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"))), 230);
+    /* Unknown, and unsigned: just the outpoint, empty scriptSig and sequence */
+    assert_eq! (input_weight (&txin (None)), 4 * 41);
+  }
+
+  #[test]
+  fn multisig_estimates() {
+    let script = hex_string_to_u8 (MULTISIG).unwrap();
+
+    /* P2SH: OP_0, two 73-byte signatures and an OP_PUSHDATA1 of the
+     * 105-byte script make a 254-byte scriptSig */
+    let mut legacy = txin (None);
+    legacy.scriptSig = ~[0u8, 0x4c, 105];
+    legacy.scriptSig.push_all (script);
+    assert_eq! (input_weight (&legacy), 4 * (36 + 3 + 254 + 4));
+
+    /* P2WSH: an empty scriptSig, then the witness: its item count, the
+     * dummy, two signatures and the script, at one weight unit a byte */
+    let mut segwit = txin (None);
+    segwit.witness = ~[~[], script.clone()];
+    assert_eq! (input_weight (&segwit), 4 * 41 + 1 + 1 + 2 * 73 + 1 + 105);
+  }
+
+  #[test]
+  fn tolerance() {
+    assert_eq! (tolerated_rate (46620, 10), 41958);
+    assert_eq! (tolerated_rate (46620, 0), 46620);
+    assert_eq! (tolerated_rate (46620, 100), 0);
+    /* No overflow, however high the rate */
+    assert_eq! (tolerated_rate (0xffffffffffffffff, 50), 0x7fffffffffffffff);
+  }
+
+  #[test]
+  fn fairness() {
+    /* The second submission pays nothing, which only full tolerance allows */
+    assert! (!check_fairness (submissions (true), 2, 2, 10, true));
+    assert! (check_fairness (submissions (true), 2, 2, 10, false));
+    assert! (check_fairness (submissions (true), 2, 2, 100, true));
+  }
+
+  #[test]
+  fn unknown_values() {
+    /* Strictly, fees we can't check are refused; otherwise let through */
+    assert! (!check_fairness (submissions (false), 2, 2, 10, true));
+    assert! (check_fairness (submissions (false), 2, 2, 10, false));
+  }
+}
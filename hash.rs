@@ -1,28 +1,227 @@
 
-use std::libc::size_t;
-use std::vec::from_buf;
+/* HASH FUNCTIONS */
+
+/* SHA256 round constants and initial state (FIPS 180-4) */
+static SHA256_K: [u32, ..64] = [
+  0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+  0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+  0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+  0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+  0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+  0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+  0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+  0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+static SHA256_H: [u32, ..8] = [
+  0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+/* RIPEMD160 message word selection, rotation amounts and round constants,
+ * for the left and right (primed) lines */
+static RIPEMD_R: [uint, ..80] = [
+  0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+  7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9, 5, 2, 14, 11, 8,
+  3, 10, 14, 4, 9, 15, 8, 1, 2, 7, 0, 6, 13, 11, 5, 12,
+  1, 9, 11, 10, 0, 8, 12, 4, 13, 3, 7, 15, 14, 5, 6, 2,
+  4, 0, 5, 9, 7, 12, 2, 10, 14, 1, 3, 8, 11, 6, 15, 13
+];
+static RIPEMD_RP: [uint, ..80] = [
+  5, 14, 7, 0, 9, 2, 11, 4, 13, 6, 15, 8, 1, 10, 3, 12,
+  6, 11, 3, 7, 0, 13, 5, 10, 14, 15, 8, 12, 4, 9, 1, 2,
+  15, 5, 1, 3, 7, 14, 6, 9, 11, 8, 12, 2, 10, 0, 4, 13,
+  8, 6, 4, 1, 3, 11, 15, 0, 5, 12, 2, 13, 9, 7, 10, 14,
+  12, 15, 10, 4, 1, 5, 8, 7, 6, 2, 13, 14, 0, 3, 9, 11
+];
+static RIPEMD_S: [uint, ..80] = [
+  11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8,
+  7, 6, 8, 13, 11, 9, 7, 15, 7, 12, 15, 9, 11, 7, 13, 12,
+  11, 13, 6, 7, 14, 9, 13, 15, 14, 8, 13, 6, 5, 12, 7, 5,
+  11, 12, 14, 15, 14, 15, 9, 8, 9, 14, 5, 6, 8, 6, 5, 12,
+  9, 15, 5, 11, 6, 8, 13, 12, 5, 12, 13, 14, 11, 8, 5, 6
+];
+static RIPEMD_SP: [uint, ..80] = [
+  8, 9, 9, 11, 13, 15, 15, 5, 7, 7, 8, 11, 14, 14, 12, 6,
+  9, 13, 15, 7, 12, 8, 9, 11, 7, 7, 12, 7, 6, 15, 13, 11,
+  9, 7, 15, 11, 8, 6, 6, 14, 12, 13, 5, 14, 13, 13, 7, 5,
+  15, 5, 8, 11, 14, 14, 6, 14, 6, 9, 12, 9, 12, 5, 15, 8,
+  8, 5, 12, 9, 12, 5, 14, 6, 8, 13, 6, 5, 15, 13, 11, 11
+];
+static RIPEMD_K: [u32, ..5] = [
+  0x00000000, 0x5a827999, 0x6ed9eba1, 0x8f1bbcdc, 0xa953fd4e
+];
+static RIPEMD_KP: [u32, ..5] = [
+  0x50a28be6, 0x5c4dd124, 0x6d703ef3, 0x7a6d76e9, 0x00000000
+];
 
+fn rotr (x: u32, n: uint) -> u32 { (x >> n) | (x << (32 - n)) }
+fn rotl (x: u32, n: uint) -> u32 { (x << n) | (x >> (32 - n)) }
 
-#[link(name = "sha-wrapper")]
-#[link(name = "crypto")]
-extern {
-  fn csha256_sum (input: *u8, len: size_t) -> *u8;
-  fn csha256_destroy (input: *u8);
+/**
+ * Merkle-Damgard padding: a 1 bit, zeros, then the message length in bits
+ * (big-endian for SHA256, little-endian for RIPEMD160)
+ */
+fn md_pad (input: &[u8], big_endian: bool) -> ~[u8]
+{
+  let mut rv = input.to_owned();
+  let bit_len = (input.len() as u64) * 8;
+  rv.push (0x80);
+  while rv.len() % 64 != 56 {
+    rv.push (0);
+  }
+  if big_endian {
+    for i in range (0, 8) {
+      rv.push ((bit_len >> (56 - 8 * i)) as u8);
+    }
+    rv
+  } else {
+    push_u64_le (rv, bit_len)
+  }
 }
 
-/* HASH FUNCTIONS */
+/**
+ * SHA256 compression function on a single 64-byte block
+ */
+fn sha256_compress (state: &mut [u32, ..8], block: &[u8])
+{
+  let mut w = [0u32, ..64];
+  for i in range (0u, 16) {
+    w[i] = ((block[4 * i] as u32) << 24) | ((block[4 * i + 1] as u32) << 16) |
+           ((block[4 * i + 2] as u32) << 8) | (block[4 * i + 3] as u32);
+  }
+  for i in range (16u, 64) {
+    let s0 = rotr (w[i - 15], 7) ^ rotr (w[i - 15], 18) ^ (w[i - 15] >> 3);
+    let s1 = rotr (w[i - 2], 17) ^ rotr (w[i - 2], 19) ^ (w[i - 2] >> 10);
+    w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+  }
+
+  let mut a = state[0]; let mut b = state[1]; let mut c = state[2]; let mut d = state[3];
+  let mut e = state[4]; let mut f = state[5]; let mut g = state[6]; let mut h = state[7];
+  for i in range (0u, 64) {
+    let s1 = rotr (e, 6) ^ rotr (e, 11) ^ rotr (e, 25);
+    let ch = (e & f) ^ (!e & g);
+    let t1 = h + s1 + ch + SHA256_K[i] + w[i];
+    let s0 = rotr (a, 2) ^ rotr (a, 13) ^ rotr (a, 22);
+    let maj = (a & b) ^ (a & c) ^ (b & c);
+    let t2 = s0 + maj;
+    h = g; g = f; f = e; e = d + t1;
+    d = c; c = b; b = a; a = t1 + t2;
+  }
+  state[0] += a; state[1] += b; state[2] += c; state[3] += d;
+  state[4] += e; state[5] += f; state[6] += g; state[7] += h;
+}
 
 /**
  * Compute a SHA256 sum of a raw bitstring
  */
 pub fn sha256_sum (input: &[u8]) -> ~[u8]
 {
-  unsafe {
-    let raw_ptr = csha256_sum (input.as_ptr(), input.len() as size_t);
-    let ret_val = from_buf (raw_ptr, 32);
-    csha256_destroy (raw_ptr);
-    ret_val
+  let mut state = SHA256_H;
+  let padded = md_pad (input, true);
+  let mut offset = 0;
+  while offset < padded.len() {
+    sha256_compress (&mut state, padded.slice (offset, offset + 64));
+    offset += 64;
+  }
+
+  let mut rv: ~[u8] = ~[];
+  for &word in state.iter() {
+    rv.push ((word >> 24) as u8);
+    rv.push ((word >> 16) as u8);
+    rv.push ((word >> 8) as u8);
+    rv.push (word as u8);
+  }
+  rv
+}
+
+/**
+ * Compute SHA256(SHA256(x)), as used for txids and checksums
+ */
+pub fn sha256d_sum (input: &[u8]) -> ~[u8]
+{
+  sha256_sum (sha256_sum (input))
+}
+
+/**
+ * BIP340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || x)
+ */
+pub fn tagged_hash (tag: &str, input: &[u8]) -> ~[u8]
+{
+  let tag_hash = sha256_sum (tag.as_bytes());
+  let mut data = tag_hash.clone();
+  data.push_all (tag_hash);
+  data.push_all (input);
+  sha256_sum (data)
+}
+
+/**
+ * The RIPEMD160 boolean function for round j
+ */
+fn ripemd_f (j: uint, x: u32, y: u32, z: u32) -> u32
+{
+  match j / 16 {
+    0 => x ^ y ^ z,
+    1 => (x & y) | (!x & z),
+    2 => (x | !y) ^ z,
+    3 => (x & z) | (y & !z),
+    _ => x ^ (y | !z)
+  }
+}
+
+/**
+ * RIPEMD160 compression function on a single 64-byte block
+ */
+fn ripemd160_compress (state: &mut [u32, ..5], block: &[u8])
+{
+  let mut x = [0u32, ..16];
+  for i in range (0u, 16) {
+    x[i] = (block[4 * i] as u32) | ((block[4 * i + 1] as u32) << 8) |
+           ((block[4 * i + 2] as u32) << 16) | ((block[4 * i + 3] as u32) << 24);
+  }
+
+  let mut al = state[0]; let mut bl = state[1]; let mut cl = state[2];
+  let mut dl = state[3]; let mut el = state[4];
+  let mut ar = state[0]; let mut br = state[1]; let mut cr = state[2];
+  let mut dr = state[3]; let mut er = state[4];
+  for j in range (0u, 80) {
+    let t = rotl (al + ripemd_f (j, bl, cl, dl) + x[RIPEMD_R[j]] + RIPEMD_K[j / 16], RIPEMD_S[j]) + el;
+    al = el; el = dl; dl = rotl (cl, 10); cl = bl; bl = t;
+    let t = rotl (ar + ripemd_f (79 - j, br, cr, dr) + x[RIPEMD_RP[j]] + RIPEMD_KP[j / 16], RIPEMD_SP[j]) + er;
+    ar = er; er = dr; dr = rotl (cr, 10); cr = br; br = t;
+  }
+  let t = state[1] + cl + dr;
+  state[1] = state[2] + dl + er;
+  state[2] = state[3] + el + ar;
+  state[3] = state[4] + al + br;
+  state[4] = state[0] + bl + cr;
+  state[0] = t;
+}
+
+/**
+ * Compute a RIPEMD160 sum of a raw bitstring
+ */
+pub fn ripemd160_sum (input: &[u8]) -> ~[u8]
+{
+  let mut state = [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+  let padded = md_pad (input, false);
+  let mut offset = 0;
+  while offset < padded.len() {
+    ripemd160_compress (&mut state, padded.slice (offset, offset + 64));
+    offset += 64;
+  }
+
+  let mut rv: ~[u8] = ~[];
+  for &word in state.iter() {
+    rv = push_u32_le (rv, word);
   }
+  rv
+}
+
+/**
+ * Compute RIPEMD160(SHA256(x)), as used for pubkey and script hashes
+ */
+pub fn hash160_sum (input: &[u8]) -> ~[u8]
+{
+  ripemd160_sum (sha256_sum (input))
 }
 
 
@@ -81,3 +280,49 @@ pub trait Hashable {
   fn to_hash(&self) -> ~[u8];
 }
 
+
+#[cfg(test)]
+mod tests {
+  use hash::{sha256_sum, sha256d_sum, ripemd160_sum, hash160_sum};
+  use util::u8_to_hex_string;
+
+  #[test]
+  fn sha256_nist_vectors() {
+    assert_eq! (u8_to_hex_string (sha256_sum ("".as_bytes())),
+                ~"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq! (u8_to_hex_string (sha256_sum ("abc".as_bytes())),
+                ~"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq! (u8_to_hex_string (sha256_sum ("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".as_bytes())),
+                ~"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    let million_a = ::std::vec::from_elem (1000000, 'a' as u8);
+    assert_eq! (u8_to_hex_string (sha256_sum (million_a)),
+                ~"cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+  }
+
+  #[test]
+  fn sha256d_vector() {
+    assert_eq! (u8_to_hex_string (sha256d_sum ("abc".as_bytes())),
+                ~"4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358");
+  }
+
+  #[test]
+  fn ripemd160_vectors() {
+    assert_eq! (u8_to_hex_string (ripemd160_sum ("".as_bytes())),
+                ~"9c1185a5c5e9fc54612808977ee8f548b2258d31");
+    assert_eq! (u8_to_hex_string (ripemd160_sum ("abc".as_bytes())),
+                ~"8eb208f7e05d987a9b044a8e98c6b087f15a0bfc");
+    assert_eq! (u8_to_hex_string (ripemd160_sum ("message digest".as_bytes())),
+                ~"5d0689ef49d2fae572b881b123a85ffa21595f36");
+  }
+
+  #[test]
+  fn hash160_vector() {
+    /* hash160 of the secp256k1 generator, compressed */
+    let g = ~[0x02u8, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95,
+              0xce, 0x87, 0x0b, 0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59,
+              0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98];
+    assert_eq! (u8_to_hex_string (hash160_sum (g)),
+                ~"751e76e8199196d454941c45d1b3a323f1433bd6");
+  }
+}
+
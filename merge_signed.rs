@@ -1,16 +1,23 @@
 
+use std::vec;
+
+use address;
+use amount::Amount;
 use transaction::{Transaction, TxIn, TxOut};
+use hash;
 use hash::Hashable;
+use multisig;
+use script;
+use secp256k1;
 use util;
 
 /**/
-fn match_input(in1: &TxIn, in2: &TxIn) -> bool
+fn match_outpoint(in1: &TxIn, in2: &TxIn) -> bool
 {
   /* We don't check scriptSig since that will be different
    * for different transactions. */
   in1.prev_hash == in2.prev_hash &&
-  in1.prev_index == in2.prev_index &&
-  in1.nSequence == in2.nSequence
+  in1.prev_index == in2.prev_index
 }
 
 fn match_output(in1: &TxOut, in2: &TxOut) -> bool
@@ -19,13 +26,151 @@ fn match_output(in1: &TxOut, in2: &TxOut) -> bool
   in1.scriptPubKey == in2.scriptPubKey
 }
 
+/**
+ * List every way in which a submission differs from the master, other than
+ * signatures and the order of inputs and outputs. Outputs are compared as
+ * a multiset, since duplicates are legal.
+ */
+fn structural_diff (master: &Transaction, tx: &Transaction, network: address::Network) -> ~[~str]
+{
+  let mut rv: ~[~str] = ~[];
+  if tx.nVersion != master.nVersion {
+    rv.push (format! ("version {:u} vs {:u}", tx.nVersion, master.nVersion));
+  }
+  if tx.nLockTime != master.nLockTime {
+    rv.push (format! ("locktime {:u} vs {:u}", tx.nLockTime, master.nLockTime));
+  }
+  if tx.input.len() != master.input.len() {
+    rv.push (format! ("{:u} inputs vs {:u}", tx.input.len(), master.input.len()));
+  }
+  if tx.output.len() != master.output.len() {
+    rv.push (format! ("{:u} outputs vs {:u}", tx.output.len(), master.output.len()));
+  }
+
+  for txin in master.input.iter() {
+    match tx.input.iter().find (|other| match_outpoint (*other, txin)) {
+      None => {
+        rv.push (format! ("missing input {:s}:{:u}", util::u8_to_hex_string (txin.prev_hash), txin.prev_index));
+      }
+      Some(other) if other.nSequence != txin.nSequence => {
+        rv.push (format! ("input {:s}:{:u} has sequence {:u} vs {:u}",
+          util::u8_to_hex_string (txin.prev_hash), txin.prev_index, other.nSequence, txin.nSequence));
+      }
+      Some(_) => {}
+    }
+  }
+  for txin in tx.input.iter() {
+    if !master.input.iter().any (|other| match_outpoint (other, txin)) {
+      rv.push (format! ("extra input {:s}:{:u}", util::u8_to_hex_string (txin.prev_hash), txin.prev_index));
+    }
+  }
+
+  /* Pair off identical outputs; whatever is left over on either side is
+   * missing or extra */
+  let mut used = vec::from_elem (tx.output.len(), false);
+  for txout in master.output.iter() {
+    match range (0, tx.output.len()).find (|&k| !used[k] && match_output (&tx.output[k], txout)) {
+      Some(k) => { used[k] = true; }
+      None => {
+        rv.push (format! ("missing output {:s} {:u}", address::script_to_str (txout.scriptPubKey, network), txout.nValue.to_sat()));
+      }
+    }
+  }
+  for (k, txout) in tx.output.iter().enumerate() {
+    if !used[k] {
+      rv.push (format! ("extra output {:s} {:u}", address::script_to_str (txout.scriptPubKey, network), txout.nValue.to_sat()));
+    }
+  }
+  rv
+}
+
+/**
+ * Check a signature, with its hash type byte, and public key against the
+ * given pubkey hash and the merged transaction's signature hash
+ */
+fn check_key_signature (master: &Transaction, index: uint, sig: &[u8], pubkey: &[u8],
+                        pubkey_hash: &[u8], segwit_amount: Option<Amount>) -> bool
+{
+  if sig.len() == 0 || hash::hash160_sum (pubkey).as_slice() != pubkey_hash {
+    return false;
+  }
+  let hash_type = sig[sig.len() - 1];
+  /* Both P2PKH and P2WPKH sign with the P2PKH script as script code */
+  let mut script_code = ~[script::OP_DUP, script::OP_HASH160, 20];
+  script_code.push_all (pubkey_hash);
+  script_code.push (script::OP_EQUALVERIFY);
+  script_code.push (script::OP_CHECKSIG);
+
+  let digest = match segwit_amount {
+    Some(amount) => master.segwit_v0_signature_hash (index, script_code, amount, hash_type),
+    None => master.signature_hash (index, script_code, hash_type)
+  };
+  secp256k1::verify (digest, sig.slice_to (sig.len() - 1), pubkey)
+}
+
+/* What we could tell about a submission's signature for an input */
+enum SignatureCheck {
+  ValidSignature,
+  InvalidSignature,
+  /* We can't check it, for the given reason */
+  UncheckedSignature(~str)
+}
+
+/**
+ * Check the signature data a submission supplied for the master's input at
+ * `index`. We can only do this when we know the output being spent, and only
+ * for P2PKH and P2WPKH.
+ */
+fn check_signature (master: &Transaction, index: uint, txin: &TxIn) -> SignatureCheck
+{
+  let prev_output = match master.input[index].prev_output {
+    Some(ref out) => out.clone(),
+    None => { return UncheckedSignature (~"the output it spends is unknown"); }
+  };
+  let spk = prev_output.scriptPubKey.as_slice();
+
+  let valid = match prev_output.script_type() {
+    script::PayToPubkeyHash => {
+      if txin.witness.len() > 0 { return InvalidSignature; }
+      let stack = match script::parse (txin.scriptSig) {
+        Some(s) => s,
+        None => { return InvalidSignature; }
+      };
+      if stack.len() != 2 { return InvalidSignature; }
+      match (&stack[0], &stack[1]) {
+        (&script::Push(_, ref sig), &script::Push(_, ref pubkey)) =>
+          check_key_signature (master, index, sig.as_slice(), pubkey.as_slice(), spk.slice (3, 23), None),
+        _ => false
+      }
+    }
+    script::PayToWitnessPubkeyHash => {
+      if txin.scriptSig.len() > 0 || txin.witness.len() != 2 { return InvalidSignature; }
+      check_key_signature (master, index, txin.witness[0], txin.witness[1],
+                           spk.slice (2, 22), Some(prev_output.nValue))
+    }
+    ty => { return UncheckedSignature (format! ("it spends a {:s} output", ty.name())); }
+  };
+  if valid { ValidSignature } else { InvalidSignature }
+}
+
+/**
+ * Whether a submission has its inputs and outputs in the same order as the
+ * master, given that structural_diff found no other difference
+ */
+fn same_order (master: &Transaction, tx: &Transaction) -> bool
+{
+  tx.input.iter().zip (master.input.iter()).all (|(a, b)| match_outpoint (a, b)) &&
+    tx.output.iter().zip (master.output.iter()).all (|(a, b)| match_output (a, b))
+}
+
 /**
  * Merge signed transactions
  * This function verifies that all the transactions are the same modulo
  * signatures, then combines them all into one big transaction that has
- * all the available signatures in place.
+ * all the available signatures in place. Signatures on multisig inputs
+ * are combined across submissions, so cosigners may each sign separately.
  */
-pub fn merge_signed_transactions (txlist: &[Transaction]) -> Option<Transaction>
+pub fn merge_signed_transactions (txlist: &[Transaction], network: address::Network) -> Option<Transaction>
 {
   if txlist.len() == 0 { return None; }
 
@@ -38,53 +183,192 @@ pub fn merge_signed_transactions (txlist: &[Transaction]) -> Option<Transaction>
    * will somehow bojangle the call to .to_hash(). */
   let master_hash = util::u8_to_hex_string (master.to_hash());
 
+  /* Signatures seen so far on each multisig input, by key (or by position,
+   * if we can't verify them), along with the script they are for */
+  let mut partial_sigs: ~[Option<(~[u8], ~[Option<~[u8]>])>] = ~[];
+  /* and whether they are enough to spend it */
+  let mut complete: ~[bool] = ~[];
+  for _ in range (0, master.input.len()) {
+    partial_sigs.push (None);
+    complete.push (false);
+  }
+
   /* Loop through all transactions, merging onto master */
-  for tx in txlist.iter() {
-    /* Check that version and locktime at least match */
-    if tx.nVersion != master.nVersion {
-      println (format! ("err: Tx {:s} did not match {:s} (version {:u} vs {:u})!",
-        util::u8_to_hex_string (master.to_hash()),
-        util::u8_to_hex_string (tx.to_hash()),
-        master.nVersion, tx.nVersion));
-      return None;
-    }
-    if tx.nLockTime != master.nLockTime {
-      println (format! ("err: Tx {:s} did not match {:s} (locktime {:u} vs {:u})!",
-        util::u8_to_hex_string (master.to_hash()),
-        util::u8_to_hex_string (tx.to_hash()),
-        master.nLockTime, tx.nLockTime));
+  for (n, tx) in txlist.iter().enumerate() {
+    /* Check that the transaction is the same as the master, up to order */
+    let diff = structural_diff (&master, tx, network);
+    if diff.len() > 0 {
+      println (format! ("err: Tx {:s} did not match {:s} ({:s})!",
+        util::u8_to_hex_string (tx.to_hash()), master_hash, diff.connect ("; ")));
       return None;
     }
 
-    /* Check that outputs match */
-    for (tx1, tx2) in tx.output.iter().zip(master.output.iter()) {
-      if !match_output (tx1, tx2) {
-        println (format! ("err: Tx {:s} did not match {:s} (output {:s}:{:u} vs {:s}:{:u})!",
-          master_hash,
-          util::u8_to_hex_string (tx.to_hash()),
-          util::u8_to_hex_string (tx1.scriptPubKey), tx1.nValue,
-          util::u8_to_hex_string (tx2.scriptPubKey), tx2.nValue));
-        return None;
+    /* A submission in another order signed another transaction, so we
+     * only take its signatures if they check out against the master */
+    let reordered = !same_order (&master, tx);
+
+    /* Find each input by outpoint (the diff above guarantees it is there)
+     * -- if a signature exists, take it */
+    for i in range (0, master.input.len()) {
+      let tx1 = tx.input.iter().find (|txin| match_outpoint (*txin, &master.input[i])).unwrap();
+      /* Multisig signatures are combined with those from other submissions
+       * rather than replacing them */
+      match multisig::parse_input (tx1) {
+        Some(ref ms) => {
+          let verified = ms.can_verify (&master, i);
+          let sigs = if verified {
+            match ms.signatures_by_key (&master, i) {
+              Some(sigs) => sigs,
+              None => {
+                println (format! ("err: Submission {:u} (tx {:s}) has an invalid signature for input {:s}:{:u}!",
+                  n + 1, util::u8_to_hex_string (tx.to_hash()),
+                  util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+                return None;
+              }
+            }
+          } else {
+            if ms.sigs.len() > 0 {
+              if reordered {
+                println (format! ("err: Submission {:u} (tx {:s}) is in a different order from tx {:s}, and its signatures for input {:s}:{:u} can't be checked, since the amount it spends is unknown!",
+                  n + 1, util::u8_to_hex_string (tx.to_hash()), master_hash,
+                  util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+                return None;
+              }
+              println (format! ("warn: Signatures for input {:s}:{:u} are unchecked, since the amount it spends is unknown.",
+                util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+            }
+            ms.signatures_by_position()
+          };
+          let combined = match partial_sigs[i] {
+            None => sigs,
+            Some((ref script, ref earlier)) => {
+              if *script != ms.script {
+                println (format! ("err: Submission {:u} (tx {:s}) uses a different script for input {:s}:{:u}!",
+                  n + 1, util::u8_to_hex_string (tx.to_hash()),
+                  util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+                return None;
+              }
+              let mut rv: ~[Option<~[u8]>] = ~[];
+              for (old, new) in earlier.iter().zip (sigs.iter()) {
+                match (old, new) {
+                  /* Two valid signatures by one key are as good as each
+                   * other, but two unchecked ones in one place may not be */
+                  (&Some(ref a), &Some(ref b)) if !verified && a != b => {
+                    println (format! ("err: Submission {:u} (tx {:s}) has a different signature than an earlier one in the same place for input {:s}:{:u}, and we can't check which is valid!",
+                      n + 1, util::u8_to_hex_string (tx.to_hash()),
+                      util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+                    return None;
+                  }
+                  (_, &Some(_)) => { rv.push (new.clone()); }
+                  _ => { rv.push (old.clone()); }
+                }
+              }
+              rv
+            }
+          };
+          complete[i] = ms.set_signatures (&mut master.input[i], combined);
+          partial_sigs[i] = Some((ms.script.clone(), combined));
+          continue;
+        }
+        None => {}
       }
-    }
 
-    /* Check that inputs match -- if they do, and a signature exists, take it */
-    for (tx1, tx2) in tx.input.iter().zip(master.input.mut_iter()) {
-      if match_input (tx1, tx2) {
-        if tx1.scriptSig.len() > 0 {
-          tx2.scriptSig = tx1.scriptSig.clone();
+      if tx1.scriptSig.len() > 0 || tx1.witness.len() > 0 {
+        /* Don't let anything replace combined multisig signatures */
+        if partial_sigs[i].is_some() {
+          println (format! ("err: Submission {:u} (tx {:s}) has a non-multisig spend of multisig input {:s}:{:u}!",
+            n + 1, util::u8_to_hex_string (tx.to_hash()),
+            util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+          return None;
+        }
+        match check_signature (&master, i, tx1) {
+          ValidSignature => {}
+          InvalidSignature => {
+            println (format! ("err: Submission {:u} (tx {:s}) has an invalid signature for input {:s}:{:u}!",
+              n + 1, util::u8_to_hex_string (tx.to_hash()),
+              util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index));
+            return None;
+          }
+          UncheckedSignature(why) => {
+            if reordered {
+              println (format! ("err: Submission {:u} (tx {:s}) is in a different order from tx {:s}, and its signature for input {:s}:{:u} can't be checked, since {:s}!",
+                n + 1, util::u8_to_hex_string (tx.to_hash()), master_hash,
+                util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index, why));
+              return None;
+            }
+            println (format! ("warn: Signature for input {:s}:{:u} is unchecked, since {:s}.",
+              util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index, why));
+          }
         }
-      } else {
-        println (format! ("err: Tx {:s} did not match {:s} (input {:s}:{:u} vs {:s}:{:u})!",
-          master_hash,
-          util::u8_to_hex_string (tx.to_hash()),
-          util::u8_to_hex_string (tx1.prev_hash), tx1.prev_index,
-          util::u8_to_hex_string (tx2.prev_hash), tx2.prev_index));
-        return None;
+      }
+      let tx2 = &mut master.input[i];
+      if tx1.scriptSig.len() > 0 {
+        tx2.scriptSig = tx1.scriptSig.clone();
+      }
+      if tx1.witness.len() > 0 {
+        tx2.witness = tx1.witness.clone();
       }
     }
   }
 
+  /* Multisig inputs may still be waiting for cosigners */
+  for (i, txin) in master.input.iter().enumerate() {
+    if partial_sigs[i].is_some() && !complete[i] {
+      println (format! ("warn: Multisig input {:s}:{:u} does not have enough signatures yet.",
+        util::u8_to_hex_string (txin.prev_hash), txin.prev_index));
+    }
+  }
+
   Some(master)
 }
 
+
+#[cfg(test)]
+mod tests {
+  use address::Mainnet;
+  use merge_signed::merge_signed_transactions;
+  use transaction;
+  use transaction::Transaction;
+  use util::hex_string_to_u8;
+
+  /* Two cosigners of a 2-of-3 P2WSH input, each leaving placeholders for
+   * the keys they don't sign with: the first signs with key 0, the second
+   * with key 2. The third submission instead has a key 1 signature in the
+   * place of key 0. */
+  static FIRST: &'static str = "01000000000101c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c30200000000ffffffff01f08cc40400000000160014c1a5d6e95317291b3ed7453af33433fb80d24d5c0500483045022100df3aecc51ea4dd464e9534e94459f9110f6b64adca8d57b19cb75aba2fdf2c4002206939eb37d384e6e8dc71b0a468cd4a19e668a552a832449adaf77dfa510e57530100006952210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f21039000d5808852f8a3a7f83736e108705c3ec0452fa056133acd668b121ca0964c53ae00000000";
+  static SECOND: &'static str = "01000000000101c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c30200000000ffffffff01f08cc40400000000160014c1a5d6e95317291b3ed7453af33433fb80d24d5c0500000047304402204329c51e57969be2c71a3960e42e78d65178607f9b00f37b21c9f2942f147dc402205efed4d5475a25b4f1e57412bde0d62cd6f3d3eb496d98134d6852e4e9312b65016952210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f21039000d5808852f8a3a7f83736e108705c3ec0452fa056133acd668b121ca0964c53ae00000000";
+  static MISPLACED: &'static str = "01000000000101c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c30200000000ffffffff01f08cc40400000000160014c1a5d6e95317291b3ed7453af33433fb80d24d5c050047304402201b0ca2fc81e0d484abb362b74a260cedb2316f4546e480e14278db505c50e8d402204065569efb977d5af4089f0435ea4094721e9066165411861a96b585eff509190100006952210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f21039000d5808852f8a3a7f83736e108705c3ec0452fa056133acd668b121ca0964c53ae00000000";
+  static BOTH: &'static str = "01000000000101c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c30200000000ffffffff01f08cc40400000000160014c1a5d6e95317291b3ed7453af33433fb80d24d5c0400483045022100df3aecc51ea4dd464e9534e94459f9110f6b64adca8d57b19cb75aba2fdf2c4002206939eb37d384e6e8dc71b0a468cd4a19e668a552a832449adaf77dfa510e57530147304402204329c51e57969be2c71a3960e42e78d65178607f9b00f37b21c9f2942f147dc402205efed4d5475a25b4f1e57412bde0d62cd6f3d3eb496d98134d6852e4e9312b65016952210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f21039000d5808852f8a3a7f83736e108705c3ec0452fa056133acd668b121ca0964c53ae00000000";
+  static PREVOUT: &'static str = "c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3:2 80000000 0020415d89396f15190031336609b4b0c35c33af278e9dfd54782deaf55f282c33d1";
+
+  fn submissions (hexes: &[&str], prevouts: bool) -> ~[Transaction] {
+    let prevout = transaction::prevout_from_str (PREVOUT).unwrap();
+    hexes.iter().map (|hex| {
+      let mut tx = transaction::from_hex (hex_string_to_u8 (*hex).unwrap()).unwrap();
+      if prevouts {
+        tx.set_prevouts ([prevout.clone()]);
+      }
+      tx
+    }).collect()
+  }
+
+  #[test]
+  fn partial_multisig() {
+    /* With the amount we can check the signatures and sort them by key */
+    let merged = merge_signed_transactions (submissions ([FIRST, SECOND], true), Mainnet).unwrap();
+    assert_eq! (merged.to_str(), BOTH.to_owned());
+    let merged = merge_signed_transactions (submissions ([SECOND, FIRST], true), Mainnet).unwrap();
+    assert_eq! (merged.to_str(), BOTH.to_owned());
+    /* and it doesn't matter where they were placed */
+    assert! (merge_signed_transactions (submissions ([FIRST, MISPLACED], true), Mainnet).is_some());
+  }
+
+  #[test]
+  fn partial_multisig_unchecked() {
+    /* Without it we go by where the signatures were placed */
+    let merged = merge_signed_transactions (submissions ([FIRST, SECOND], false), Mainnet).unwrap();
+    assert_eq! (merged.to_str(), BOTH.to_owned());
+    /* and refuse to pick between two in the same place */
+    assert! (merge_signed_transactions (submissions ([FIRST, MISPLACED], false), Mainnet).is_none());
+  }
+}
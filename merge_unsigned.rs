@@ -1,28 +1,335 @@
 
-use std::rand;
-use std::rand::Rng;
+use std::cmp;
+use std::cmp::{Ordering, Equal};
+use std::vec;
 
-use transaction::{Transaction, TxIn};
+use address;
+use amount::Amount;
+use fee;
+use transaction::{Transaction, TxIn, TxOut, SIGHASH_SINGLE, SIGHASH_ANYONECANPAY};
 use hash::Hashable;
+use multisig;
+use seed;
 use util;
 
+/**
+ * What to do when two outputs pay the same scriptPubKey
+ */
+pub enum DuplicateOutputPolicy {
+  /* Combine them into one output (which reveals that their owners paid
+   * the same address, and changes their outputs) */
+  SumDuplicates,
+  /* Leave them as separate outputs */
+  KeepDuplicates,
+  /* Refuse to merge */
+  RejectDuplicates
+}
+
+/**
+ * Parse a duplicate output policy as given on the command line
+ */
+pub fn duplicate_policy_from_str (name: &str) -> Option<DuplicateOutputPolicy>
+{
+  match name {
+    "sum" => Some(SumDuplicates),
+    "keep" => Some(KeepDuplicates),
+    "reject" => Some(RejectDuplicates),
+    _ => None
+  }
+}
+
+/**
+ * How to order the inputs and outputs of the merged transaction
+ */
+pub enum OrderPolicy {
+  /* Shuffle them, using the seed */
+  RandomOrder,
+  /* Sort them per BIP69, so that anyone can reproduce the result */
+  Bip69Order
+}
+
+/**
+ * Parse an order policy as given on the command line
+ */
+pub fn order_policy_from_str (name: &str) -> Option<OrderPolicy>
+{
+  match name {
+    "random" => Some(RandomOrder),
+    "bip69" => Some(Bip69Order),
+    _ => None
+  }
+}
+
+/**
+ * Options controlling how submissions are merged
+ */
+pub struct MergeOptions {
+  duplicate_outputs: DuplicateOutputPolicy,
+  order: OrderPolicy,
+  /* Seed for the shuffle; see seed.rs */
+  seed: ~[u8],
+  /* How many percent below their share of the fee a submission may pay */
+  fee_tolerance: uint,
+  /* Whether to refuse to merge if someone pays less than that */
+  strict_fees: bool,
+  /* Output values everyone should use; if any are given, each submission
+   * needs at least one output of one of them and at most one change output */
+  denominations: ~[Amount],
+  /* For showing scriptPubKeys as addresses in messages */
+  network: address::Network
+}
+
+impl MergeOptions {
+  /** The defaults, which are what the merger has always done */
+  pub fn new () -> MergeOptions {
+    MergeOptions {
+      duplicate_outputs: SumDuplicates,
+      order: RandomOrder,
+      seed: seed::random(),
+      fee_tolerance: 10,
+      strict_fees: false,
+      denominations: ~[],
+      network: address::Mainnet
+    }
+  }
+}
+
 /**/
-fn match_input(in1: &TxIn, in2: &TxIn) -> bool
+fn match_outpoint(in1: &TxIn, in2: &TxIn) -> bool
 {
-  /* We don't check scriptSig since that will be different
-   * for different transactions. */
+  /* We don't check scriptSig since that will be different for different
+   * transactions, nor nSequence, since spending the same output twice is
+   * a double-spend whatever the sequence numbers. */
   in1.prev_hash == in2.prev_hash &&
-  in1.prev_index == in2.prev_index &&
-  in1.nSequence == in2.nSequence
+  in1.prev_index == in2.prev_index
+}
+
+/**
+ * Find the submission an input came from, and its index there
+ */
+fn find_original<'a> (txlist: &'a [Transaction], txin: &TxIn) -> Option<(&'a Transaction, uint)>
+{
+  for tx in txlist.iter() {
+    for (n, orig) in tx.input.iter().enumerate() {
+      if match_outpoint (orig, txin) {
+        return Some((tx, n));
+      }
+    }
+  }
+  None
+}
+
+/**
+ * Check whether a signature with the given hash type on the merged input at
+ * `index` is still valid, by checking that its signature hash is unchanged
+ * from the submission it came from. The script code is committed to in the
+ * same way by both hashes, so for this comparison we can get away without
+ * knowing the real one.
+ */
+fn signature_survives (txlist: &[Transaction], merged: &Transaction, index: uint, hash_type: u8) -> bool
+{
+  let txin = &merged.input[index];
+  /* Without ANYONECANPAY a signature commits to every input, so it cannot
+   * survive anyone else's inputs being added (for taproot too; see
+   * Transaction::taproot_signature_hash) */
+  if hash_type & SIGHASH_ANYONECANPAY == 0 {
+    return false;
+  }
+
+  match find_original (txlist, txin) {
+    None => false,
+    Some((tx, n)) => {
+      if txin.taproot_key_spend_hash_type().is_some() {
+        /* Likewise the spent outputs, for taproot (where we fill in
+         * any we don't know with blanks) */
+        let orig_hash = tx.taproot_signature_hash (n, tx.spent_outputs(), hash_type, txin.taproot_annex());
+        let merged_hash = merged.taproot_signature_hash (index, merged.spent_outputs(), hash_type, txin.taproot_annex());
+        orig_hash.is_some() && orig_hash == merged_hash
+      } else if txin.witness.len() > 0 {
+        /* Likewise the amount, for segwit v0 inputs */
+        tx.segwit_v0_signature_hash (n, &[], Amount::zero(), hash_type) ==
+          merged.segwit_v0_signature_hash (index, &[], Amount::zero(), hash_type)
+      } else {
+        tx.signature_hash (n, &[], hash_type) == merged.signature_hash (index, &[], hash_type)
+      }
+    }
+  }
+}
+
+/**
+ * If some signature on an input is SINGLE|ANYONECANPAY, return the output
+ * it signs in the submission it came from, along with its index there
+ */
+fn single_output (txlist: &[Transaction], txin: &TxIn) -> Option<(uint, TxOut)>
+{
+  let single_acp = SIGHASH_SINGLE | SIGHASH_ANYONECANPAY;
+  if !txin.signature_hash_types().iter().any (|&ht| ht & (0x1f | SIGHASH_ANYONECANPAY) == single_acp) {
+    return None;
+  }
+  match find_original (txlist, txin) {
+    Some((tx, n)) if n < tx.output.len() => Some((n, tx.output[n].clone())),
+    _ => None
+  }
+}
+
+/**
+ * Randomize the order of the inputs and outputs, except that each input
+ * with a SINGLE|ANYONECANPAY signature goes at the same index as the
+ * output it signs, so that the signature survives. Legacy signatures also
+ * commit to that index (through the number of outputs hashed), so those
+ * inputs stay where they were in their submission. Inputs which can't be
+ * placed are shuffled like the rest, and will lose their signatures; we
+ * return their outpoints, with the reason.
+ *
+ * The result depends only on the seed and the set of inputs and outputs,
+ * not on the order of submissions, since we put those in BIP69 order first.
+ */
+fn shuffle (txlist: &[Transaction], master: &mut Transaction, seed: &[u8]) -> ~[(~[u8], u32, ~str)]
+{
+  master.input.sort_by (bip69_input_order);
+  master.output.sort_by (bip69_output_order);
+
+  let mut rng = seed::HashRng::new (seed);
+  let slots = cmp::min (master.input.len(), master.output.len());
+  let mut input_at: ~[Option<uint>] = vec::from_elem (master.input.len(), None);
+  let mut output_at: ~[Option<uint>] = vec::from_elem (master.output.len(), None);
+  let mut input_used = vec::from_elem (master.input.len(), false);
+  let mut output_used = vec::from_elem (master.output.len(), false);
+  let mut unpinned: ~[(~[u8], u32, ~str)] = ~[];
+
+  /* Pin down the SINGLE|ANYONECANPAY pairs */
+  for (i, txin) in master.input.iter().enumerate() {
+    let (orig_index, paired) = match single_output (txlist, txin) {
+      Some(p) => p,
+      None => { continue; }
+    };
+    let o = match range (0, master.output.len()).find (|&o| !output_used[o] &&
+                                                        master.output[o].scriptPubKey == paired.scriptPubKey &&
+                                                        master.output[o].nValue == paired.nValue) {
+      Some(o) => o,
+      None => {
+        unpinned.push ((txin.prev_hash.clone(), txin.prev_index, ~"the output it signs was changed by the merge"));
+        continue;
+      }
+    };
+    let k = if txin.witness.len() == 0 {
+      if orig_index >= slots {
+        unpinned.push ((txin.prev_hash.clone(), txin.prev_index,
+          format! ("it must stay at index {:u}, which is past the end of the inputs or outputs", orig_index)));
+        continue;
+      }
+      if input_at[orig_index].is_some() {
+        unpinned.push ((txin.prev_hash.clone(), txin.prev_index,
+          format! ("it must stay at index {:u}, which another pinned input already has", orig_index)));
+        continue;
+      }
+      orig_index
+    } else {
+      let free: ~[uint] = range (0, slots).filter (|&k| input_at[k].is_none()).collect();
+      if free.len() == 0 {
+        unpinned.push ((txin.prev_hash.clone(), txin.prev_index, ~"other pinned inputs have every index"));
+        continue;
+      }
+      free[rng.gen_below (free.len())]
+    };
+
+    input_at[k] = Some(i);
+    output_at[k] = Some(o);
+    input_used[i] = true;
+    output_used[o] = true;
+  }
+
+  /* Shuffle everything else into the remaining places */
+  let mut rest_in: ~[uint] = range (0, master.input.len()).filter (|&i| !input_used[i]).collect();
+  let mut rest_out: ~[uint] = range (0, master.output.len()).filter (|&o| !output_used[o]).collect();
+  rng.shuffle (rest_in);
+  rng.shuffle (rest_out);
+  let mut rest_in = rest_in.move_iter();
+  let mut rest_out = rest_out.move_iter();
+  for slot in input_at.mut_iter() {
+    if slot.is_none() { *slot = rest_in.next(); }
+  }
+  for slot in output_at.mut_iter() {
+    if slot.is_none() { *slot = rest_out.next(); }
+  }
+
+  let inputs: ~[TxIn] = input_at.iter().map (|i| master.input[i.unwrap()].clone()).collect();
+  let outputs: ~[TxOut] = output_at.iter().map (|o| master.output[o.unwrap()].clone()).collect();
+  master.input = inputs;
+  master.output = outputs;
+  unpinned
+}
+
+/**
+ * BIP69 order of inputs: by previous txid (compared in the usual
+ * byte-reversed display order), then output index
+ */
+fn bip69_input_order (a: &TxIn, b: &TxIn) -> Ordering
+{
+  let a_txid: ~[u8] = a.prev_hash.rev_iter().map (|&x| x).collect();
+  let b_txid: ~[u8] = b.prev_hash.rev_iter().map (|&x| x).collect();
+  match a_txid.cmp (&b_txid) {
+    Equal => a.prev_index.cmp (&b.prev_index),
+    ord => ord
+  }
+}
+
+/**
+ * BIP69 order of outputs: by amount, then scriptPubKey
+ */
+fn bip69_output_order (a: &TxOut, b: &TxOut) -> Ordering
+{
+  match a.nValue.to_sat().cmp (&b.nValue.to_sat()) {
+    Equal => a.scriptPubKey.cmp (&b.scriptPubKey),
+    ord => ord
+  }
+}
+
+/**
+ * Check a submission against the denomination policy, if there is one.
+ * It has to add to the anonymity set: at least one output of a
+ * denomination, at most one change output, and no address paid twice,
+ * whether within it or by an output already in `merged`.
+ */
+fn check_denominations (tx: &Transaction, n: uint, merged: &Transaction, options: &MergeOptions) -> bool
+{
+  if options.denominations.len() == 0 { return true; }
+
+  let denominated = tx.output.iter().filter (|txout| options.denominations.contains (&txout.nValue)).count();
+  let change = tx.output.len() - denominated;
+  if denominated == 0 {
+    let denoms: ~[~str] = options.denominations.iter().map (|d| d.to_str()).collect();
+    println (format! ("err: Submission {:u} (tx {:s}) has no output of {:s}. Refusing to merge.",
+      n + 1, util::u8_to_hex_string (tx.to_hash()), denoms.connect (" or ")));
+    return false;
+  }
+  if change > 1 {
+    println (format! ("err: Submission {:u} (tx {:s}) has {:u} change outputs, but only one is allowed. Refusing to merge.",
+      n + 1, util::u8_to_hex_string (tx.to_hash()), change));
+    return false;
+  }
+  /* Whether or not the outputs get summed, paying one address twice
+   * links them, whoever asked for the second */
+  for (k, txout) in tx.output.iter().enumerate() {
+    if merged.output.iter().any (|other| other.scriptPubKey == txout.scriptPubKey) ||
+       tx.output.slice_to (k).iter().any (|other| other.scriptPubKey == txout.scriptPubKey) {
+      println (format! ("err: Submission {:u} (tx {:s}) pays {:s} again, which links outputs and shrinks the anonymity set. Refusing to merge.",
+        n + 1, util::u8_to_hex_string (tx.to_hash()), address::script_to_str (txout.scriptPubKey, options.network)));
+      return false;
+    }
+  }
+  true
 }
 
 /**
  * Merge unsigned transactions
  * This function takes a bunch of transactions and creates a new, big
  * transaction with all the inputs and outputs from the originals, but
- * no signatures. It also randomizes the ordering.
+ * no signatures, except those which are still valid. It also randomizes
+ * the ordering, or sorts it per BIP69 if asked, and checks that everyone
+ * pays their share of the fee when the input values are known.
  */
-pub fn merge_unsigned_transactions (txlist: &[Transaction]) -> Option<Transaction>
+pub fn merge_unsigned_transactions (txlist: &[Transaction], options: &MergeOptions) -> Option<Transaction>
 {
   if txlist.len() == 0 { return None; }
 
@@ -35,8 +342,33 @@ pub fn merge_unsigned_transactions (txlist: &[Transaction]) -> Option<Transactio
     input: ~[], output: ~[]
   };
 
+  /* Which submission each input and output of master came from (for
+   * summed outputs, the first) */
+  let mut input_owner: ~[uint] = ~[];
+  let mut output_owner: ~[uint] = ~[];
+
+  /* Whether we were told the value of any input at all; if not, there's
+   * no point complaining about each one we weren't */
+  let values_known = txlist.iter().any (|tx| tx.input.iter().any (|txin| txin.prev_output.is_some()));
+
   /* Loop through all transactions, merging onto master */
-  for tx in txlist.iter() {
+  for (n, tx) in txlist.iter().enumerate() {
+    /* If we know what this submission's inputs are worth, make sure they
+     * pay for its outputs; otherwise it would be spending other people's
+     * inputs on its outputs, and leaving them short on fee */
+    match (tx.input_value(), tx.output_value()) {
+      (Some(ins), Some(outs)) if ins < outs => {
+        println (format! ("err: Submission {:u} (tx {:s}) has outputs worth {:s} but inputs worth only {:s}. Refusing to merge.",
+          n + 1, util::u8_to_hex_string (tx.to_hash()), outs.to_str(), ins.to_str()));
+        return None;
+      }
+      (None, _) if values_known => {
+        println (format! ("warn: Inputs of submission {:u} (tx {:s}) have unknown values, so we can't check that it pays for its outputs.",
+          n + 1, util::u8_to_hex_string (tx.to_hash())));
+      }
+      _ => {}
+    }
+
     /* Check that version and locktime match, because otherwise it's unclear
      * what to do. (I guess it doesn't matter, in principle some humans will
      * verify this before it gets signed..) */
@@ -55,17 +387,49 @@ pub fn merge_unsigned_transactions (txlist: &[Transaction]) -> Option<Transactio
       return None;
     }
 
-    /* Pile all the outputs on -- check for duplicate outputs and sum them */
-    for tx in tx.output.iter() {
+    if !check_denominations (tx, n, &master, options) {
+      return None;
+    }
+
+    /* Pile all the outputs on -- check for duplicate outputs and deal with
+     * them according to policy */
+    for txout in tx.output.iter() {
       let mut already_present = false;
-      for tx_dup in master.output.mut_iter() {
-        if tx_dup.scriptPubKey == tx.scriptPubKey {
-          tx_dup.nValue += tx.nValue;
-          already_present = true;
+      for (k, tx_dup) in master.output.mut_iter().enumerate() {
+        if tx_dup.scriptPubKey == txout.scriptPubKey {
+          match options.duplicate_outputs {
+            SumDuplicates => {
+              tx_dup.nValue = match tx_dup.nValue.checked_add (txout.nValue) {
+                Some(sum) => sum,
+                None => {
+                  println (format! ("err: Outputs to {:s} add up to more than MAX_MONEY!",
+                    address::script_to_str (txout.scriptPubKey, options.network)));
+                  return None;
+                }
+              };
+              already_present = true;
+            }
+            KeepDuplicates => {}
+            RejectDuplicates => {
+              let first = output_owner[k];
+              if first == n {
+                println (format! ("err: Duplicate output to {:s} appears twice in submission {:u} (tx {:s}). Refusing to merge.",
+                  address::script_to_str (txout.scriptPubKey, options.network),
+                  n + 1, util::u8_to_hex_string (tx.to_hash())));
+              } else {
+                println (format! ("err: Duplicate output to {:s} in submission {:u} (tx {:s}) and submission {:u} (tx {:s}). Refusing to merge.",
+                  address::script_to_str (txout.scriptPubKey, options.network),
+                  first + 1, util::u8_to_hex_string (txlist[first].to_hash()),
+                  n + 1, util::u8_to_hex_string (tx.to_hash())));
+              }
+              return None;
+            }
+          }
         }
       }
       if !already_present {
-        master.output.push (tx.clone());
+        master.output.push (txout.clone());
+        output_owner.push (n);
       }
     }
 
@@ -73,31 +437,93 @@ pub fn merge_unsigned_transactions (txlist: &[Transaction]) -> Option<Transactio
      * guaranteed to be a mistake. (Probably there are also duplicate outputs,
      * but those are legal, so I don't want to delete them.) POLS says we
      * crash. */
-    for tx in tx.input.iter() {
-      for tx_dup in master.input.iter() {
-        if match_input (tx, tx_dup) {
-          println (format! ("err: Duplicate input {:s}:{:u} detected. Cowardly refusing to merge.",
-            util::u8_to_hex_string (tx.prev_hash), tx.prev_index));
+    for txin in tx.input.iter() {
+      for (k, tx_dup) in master.input.iter().enumerate() {
+        if match_outpoint (txin, tx_dup) {
+          let first = input_owner[k];
+          if first == n {
+            println (format! ("err: Duplicate input {:s}:{:u} appears twice in submission {:u} (tx {:s}). Cowardly refusing to merge.",
+              util::u8_to_hex_string (txin.prev_hash), txin.prev_index,
+              n + 1, util::u8_to_hex_string (tx.to_hash())));
+          } else {
+            println (format! ("err: Duplicate input {:s}:{:u} in submission {:u} (tx {:s}), already spent by submission {:u} (tx {:s}) which came first. Cowardly refusing to merge.",
+              util::u8_to_hex_string (txin.prev_hash), txin.prev_index,
+              n + 1, util::u8_to_hex_string (tx.to_hash()),
+              first + 1, util::u8_to_hex_string (txlist[first].to_hash())));
+          }
           return None;
         }
       }
-      let mut new_tx = tx.clone();
-      /* Remove any existing signature, except in the case that the sighash type
-       * is NONE|ANYONECANPAY, since this is the only signature type that will
-       * remain valid after a merger. (Actually, this is not true -- CodeShark
-       * has a multisigner which stores some sort of information in here, which
-       * is destroyed when I clear it. So TODO support this somehow.) */
-      if new_tx.nHashType != 0x82 {
-        new_tx.scriptSig = ~[];
-      }
-      master.input.push (new_tx);
+      master.input.push (txin.clone());
+      input_owner.push (n);
     }
   }
 
-  /* Randomize the inputs and outputs */
-  let mut rng = rand::task_rng();
-  rng.shuffle_mut (master.input);
-  rng.shuffle_mut (master.output);
+  /* Make sure nobody is getting their fee paid by the others */
+  if !fee::check_fairness (txlist, master.input.len(), master.output.len(),
+                           options.fee_tolerance, options.strict_fees) {
+    return None;
+  }
+
+  /* Randomize the inputs and outputs, or sort them. (Sorting ignores
+   * SINGLE|ANYONECANPAY pairs, whose signatures are then unlikely to
+   * survive.) */
+  let unpinned = match options.order {
+    RandomOrder => shuffle (txlist, &mut master, options.seed),
+    Bip69Order => {
+      master.input.sort_by (bip69_input_order);
+      master.output.sort_by (bip69_output_order);
+      master.input.iter().filter (|txin| single_output (txlist, *txin).is_some())
+        .map (|txin| (txin.prev_hash.clone(), txin.prev_index, ~"BIP69 order doesn't keep it with its output"))
+        .collect()
+    }
+  };
+
+  /* Remove any existing signature which is no longer valid in the merged
+   * transaction. Multisig inputs keep their redeem or witness script, with
+   * a placeholder for each key's signature, so that cosigners (and tools
+   * like CodeShark's multisigner) still know what to sign. Any signatures
+   * that did survive are kept, in the slots of their keys: found by
+   * checking them if we can, otherwise by where they were. */
+  for n in range (0, master.input.len()) {
+    /* Say so when a SINGLE|ANYONECANPAY pair, which the participant
+     * expected to keep, is broken up */
+    if single_output (txlist, &master.input[n]).is_some() &&
+       !master.input[n].signature_hash_types().iter().all (|&ht| signature_survives (txlist, &master, n, ht)) {
+      let txin = &master.input[n];
+      let why = match unpinned.iter().find (|&&(ref hash, index, _)| *hash == txin.prev_hash && index == txin.prev_index) {
+        Some(&(_, _, ref why)) => why.clone(),
+        None => ~"the merge changed what it signs"
+      };
+      println (format! ("warn: Input {:s}:{:u} is signed SINGLE|ANYONECANPAY, but {:s}, so its signature is dropped.",
+        util::u8_to_hex_string (txin.prev_hash), txin.prev_index, why));
+    }
+
+    match multisig::parse_input (&master.input[n]) {
+      Some(ms) => {
+        let kept: ~[~[u8]] = ms.sigs.iter()
+          .filter (|sig| signature_survives (txlist, &master, n, sig[sig.len() - 1]))
+          .map (|sig| sig.clone()).collect();
+        let by_key = match ms.match_signatures (&master, n, kept) {
+          Some(by_key) => by_key,
+          None => ms.signatures_by_position().move_iter().map (|sig| match sig {
+            Some(sig) if kept.contains (&sig) => Some(sig),
+            _ => None
+          }).collect()
+        };
+        ms.set_signatures (&mut master.input[n], by_key);
+      }
+      None => {
+        let hash_types = master.input[n].signature_hash_types();
+        let survives = hash_types.len() > 0 &&
+          hash_types.iter().all (|&ht| signature_survives (txlist, &master, n, ht));
+        if !survives {
+          master.input[n].scriptSig = ~[];
+          master.input[n].witness = ~[];
+        }
+      }
+    }
+  }
 
   Some(master)
 }
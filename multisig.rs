@@ -0,0 +1,187 @@
+
+use std::vec;
+
+use amount::Amount;
+use hash;
+use script;
+use secp256k1;
+use transaction::{Transaction, TxIn};
+
+/**
+ * An input spending a P2SH or P2WSH (possibly nested in P2SH) m-of-n
+ * CHECKMULTISIG script, split into its script and signatures
+ */
+pub struct MultisigInput {
+  m: uint,
+  keys: ~[~[u8]],
+  /* The redeem script, or witness script if `segwit` */
+  script: ~[u8],
+  segwit: bool,
+  /* The signatures present, including hash type bytes, in the order given */
+  sigs: ~[~[u8]],
+  /* Everything between the dummy element and the script: the signatures,
+   * and placeholders (empty) for those missing */
+  slots: ~[~[u8]]
+}
+
+/**
+ * Parse an input's scriptSig or witness as a multisig spend. Placeholder
+ * OP_0s for missing signatures, as left by partial signing, are allowed.
+ */
+pub fn parse_input (txin: &TxIn) -> Option<MultisigInput>
+{
+  let (items, segwit) = if txin.witness.len() > 0 {
+    (txin.witness.clone(), true)
+  } else {
+    let ins = match script::parse (txin.scriptSig) {
+      Some(ins) => ins,
+      None => { return None; }
+    };
+    let mut items: ~[~[u8]] = ~[];
+    for i in ins.iter() {
+      match *i {
+        script::Push(_, ref data) => { items.push (data.clone()); }
+        script::Op(_) => { return None; }
+      }
+    }
+    (items, false)
+  };
+
+  /* CHECKMULTISIG's dummy element, the signatures, then the script */
+  if items.len() < 2 || items[0].len() != 0 { return None; }
+  let redeem = items[items.len() - 1].clone();
+  let (m, keys) = match script::parse_multisig (redeem) {
+    Some(mk) => mk,
+    None => { return None; }
+  };
+  let slots = items.slice (1, items.len() - 1);
+  if slots.len() > keys.len() { return None; }
+  let sigs: ~[~[u8]] = slots.iter().filter (|s| s.len() > 0).map (|s| s.clone()).collect();
+  if !sigs.iter().all (|s| script::is_signature (*s)) { return None; }
+
+  Some(MultisigInput { m: m, keys: keys, script: redeem, segwit: segwit, sigs: sigs, slots: slots.to_owned() })
+}
+
+impl MultisigInput {
+  /**
+   * Check that the script is the one committed to by the output being spent,
+   * if we know it. For nested P2SH-P2WSH the scriptSig is the witness program.
+   */
+  fn matches_output (&self, txin: &TxIn) -> bool {
+    let prog_hash = hash::sha256_sum (self.script);
+    if self.segwit && txin.scriptSig.len() > 0 {
+      let mut program = ~[script::OP_0, 32];
+      program.push_all (prog_hash);
+      let expected = script::serialize ([script::push_data (program)]);
+      if txin.scriptSig != expected { return false; }
+    }
+
+    match txin.prev_output {
+      None => true,
+      Some(ref out) => {
+        let spk = out.scriptPubKey.as_slice();
+        match out.script_type() {
+          script::PayToScriptHash if self.segwit => {
+            txin.scriptSig.len() > 0 &&
+              spk.slice (2, 22) == hash::hash160_sum (txin.scriptSig.slice_from (1)).as_slice()
+          }
+          script::PayToScriptHash => spk.slice (2, 22) == hash::hash160_sum (self.script).as_slice(),
+          script::PayToWitnessScriptHash => self.segwit && spk.slice (2, 34) == prog_hash.as_slice(),
+          _ => false
+        }
+      }
+    }
+  }
+
+  /**
+   * Whether we can check signatures on input `index` of `tx`. Segwit
+   * signatures commit to the amount spent, which we may not know.
+   */
+  pub fn can_verify (&self, tx: &Transaction, index: uint) -> bool {
+    !self.segwit || tx.input[index].prev_output.is_some()
+  }
+
+  /**
+   * Work out which key each signature belongs to, by verifying it against
+   * `tx`'s signature hash for input `index`. Returns the signatures indexed
+   * by key, or None if the script doesn't match the output being spent or
+   * some signature is valid for no key.
+   */
+  pub fn signatures_by_key (&self, tx: &Transaction, index: uint) -> Option<~[Option<~[u8]>]> {
+    self.match_signatures (tx, index, self.sigs)
+  }
+
+  /** As `signatures_by_key`, for some other list of signatures */
+  pub fn match_signatures (&self, tx: &Transaction, index: uint, sigs: &[~[u8]]) -> Option<~[Option<~[u8]>]> {
+    let txin = &tx.input[index];
+    if !self.matches_output (txin) { return None; }
+    let amount = match txin.prev_output {
+      Some(ref out) => out.nValue,
+      None => Amount::zero()
+    };
+
+    let mut rv = vec::from_elem (self.keys.len(), None);
+    for sig in sigs.iter() {
+      let hash_type = sig[sig.len() - 1];
+      let digest = if self.segwit {
+        tx.segwit_v0_signature_hash (index, self.script, amount, hash_type)
+      } else {
+        tx.signature_hash (index, self.script, hash_type)
+      };
+      match self.keys.iter().position (|key| secp256k1::verify (digest, sig.slice_to (sig.len() - 1), *key)) {
+        Some(k) => { rv[k] = Some(sig.clone()); }
+        None => { return None; }
+      }
+    }
+    Some(rv)
+  }
+
+  /**
+   * The signatures indexed by their position among the slots, for when we
+   * can't verify them to find their keys. A cosigner who leaves a
+   * placeholder for every key puts each signature in the slot of its key.
+   */
+  pub fn signatures_by_position (&self) -> ~[Option<~[u8]>] {
+    let mut rv = vec::from_elem (self.keys.len(), None);
+    for (k, slot) in self.slots.iter().enumerate() {
+      if slot.len() > 0 {
+        rv[k] = Some(slot.clone());
+      }
+    }
+    rv
+  }
+
+  /**
+   * Rebuild an input's scriptSig or witness from signatures indexed by key.
+   * Once there are m of them we use the first m in key order, which is
+   * what CHECKMULTISIG needs. Until then every key gets a slot, holding its
+   * signature or an OP_0 placeholder, so that cosigners can see where to
+   * put theirs and the input can be combined again later.
+   * Returns whether the input is now fully signed.
+   */
+  pub fn set_signatures (&self, txin: &mut TxIn, by_key: &[Option<~[u8]>]) -> bool {
+    let sigs: ~[~[u8]] = by_key.iter().filter_map (|sig| sig.clone()).collect();
+    let complete = sigs.len() >= self.m;
+    let mut items: ~[~[u8]] = ~[~[]];
+    if complete {
+      items.push_all (sigs.slice_to (self.m));
+    } else {
+      for sig in by_key.iter() {
+        items.push (match *sig {
+          Some(ref sig) => sig.clone(),
+          None => ~[]
+        });
+      }
+    }
+    items.push (self.script.clone());
+
+    if self.segwit {
+      txin.witness = items;
+    } else {
+      let pushes: ~[script::Instruction] = items.move_iter().map (|item| script::push_data (item)).collect();
+      txin.scriptSig = script::serialize (pushes);
+    }
+    complete
+  }
+}
+
@@ -6,14 +6,12 @@ SIGNED=./coinjoin-merge-signed
 
 process_output()
 {
-  local prog="$1"
-  local input="$2"
-  local ln
+  local raw="$1"
 
   # Run the program output through an awk script which
   # simply filters for the actual data output and echos
   # it in a well-defined order.
-  "$prog" < "$input" | awk '
+  awk '
   function despace(s) {
     gsub(/[[:space:]]*/, "", s);
     return s;
@@ -41,40 +39,73 @@ process_output()
     print "mpc:", mpc;
     print "hex:", hex;
     print "err:", err;
-  }'
+  }' < "$raw"
+}
+
+# Any lines of the expected output after the first four must appear,
+# exactly, somewhere in the program's output. Print those which don't.
+missing_lines()
+{
+  local expf="$1"
+  local raw="$2"
+
+  tail -n +5 "$expf" | while IFS= read -r line
+  do
+    grep -qxF -- "$line" "$raw" || echo "$line"
+  done
 }
 
 
+# Run every test in the suites under $2 through the program $1
+run_suites()
+{
+  local prog="$1"
+  local dir="$2"
 
-# Run unsigned tests
-for suite in $TESTDIR/unsigned/*
-do
-  for run in $suite/*.input
+  for suite in $dir/*
   do
-    if [[ -f $run ]]
-    then
-      echo -n "$UNSIGNED: Running $run... ";
-      outf=$(echo $run | sed 's/input$/output/')
-      expf=$(echo $run | sed 's/input$/expected/')
-      if [[ -f "$expf" ]]
+    for run in $suite/*.input
+    do
+      if [[ -f $run ]]
       then
-        process_output $UNSIGNED "$run" > "$outf"
-        diff -q "$expf" "$outf" > /dev/null
-        if [[ "$?" == "0" ]]
-        then echo "success."
+        echo -n "$prog: Running $run... ";
+        outf=$(echo $run | sed 's/input$/output/')
+        expf=$(echo $run | sed 's/input$/expected/')
+        argf=$(echo $run | sed 's/input$/args/')
+        args=""
+        if [[ -f "$argf" ]]
+        then args=$(cat "$argf")
+        fi
+        rawf=$(echo $run | sed 's/input$/raw/')
+        if [[ -f "$expf" ]]
+        then
+          $prog $args < "$run" > "$rawf"
+          process_output "$rawf" > "$outf"
+          missing=$(missing_lines "$expf" "$rawf")
+          head -n 4 "$expf" | diff -q - "$outf" > /dev/null
+          if [[ "$?" == "0" && -z "$missing" ]]
+          then echo "success."
+          else
+            echo "failed."
+            echo "Diff output:"
+            head -n 4 "$expf" | diff - "$outf"
+            if [[ -n "$missing" ]]
+            then
+              echo "Missing lines:"
+              echo "$missing"
+            fi
+          fi
+          rm $outf $rawf
+        elif [[ -e "$expf" ]]
+        then
+          echo "failed (expected output file not an ordinary file)."
         else
-          echo "failed."
-          echo "Diff output:"
-          diff "$expf" "$outf"
+          echo "failed (no expected output file)."
         fi
-        rm $outf
-      elif [[ -e "$expf" ]]
-      then
-        echo "failed (expected output file not an ordinary file)."
-      else
-        echo "failed (no expected output file)."
       fi
-    fi
+    done
   done
-done
+}
 
+run_suites $UNSIGNED $TESTDIR/unsigned
+run_suites $SIGNED $TESTDIR/signed
@@ -0,0 +1,680 @@
+
+use std::vec::VecIterator;
+
+use util;
+
+/* Opcodes which we refer to by name */
+pub static OP_0: u8 = 0x00;
+pub static OP_PUSHDATA1: u8 = 0x4c;
+pub static OP_PUSHDATA2: u8 = 0x4d;
+pub static OP_PUSHDATA4: u8 = 0x4e;
+pub static OP_1NEGATE: u8 = 0x4f;
+pub static OP_1: u8 = 0x51;
+pub static OP_16: u8 = 0x60;
+pub static OP_RETURN: u8 = 0x6a;
+pub static OP_DUP: u8 = 0x76;
+pub static OP_EQUAL: u8 = 0x87;
+pub static OP_EQUALVERIFY: u8 = 0x88;
+pub static OP_HASH160: u8 = 0xa9;
+pub static OP_CODESEPARATOR: u8 = 0xab;
+pub static OP_CHECKSIG: u8 = 0xac;
+pub static OP_CHECKMULTISIG: u8 = 0xae;
+
+/**
+ * A single script instruction. Pushes remember which opcode they used so
+ * that non-minimal pushes reserialize to exactly the same bytes.
+ */
+pub enum Instruction {
+  Push(u8, ~[u8]),
+  Op(u8)
+}
+
+impl Clone for Instruction {
+  fn clone(&self) -> Instruction
+  {
+    match *self {
+      Push(op, ref data) => Push(op, data.clone()),
+      Op(op) => Op(op)
+    }
+  }
+}
+
+/**
+ * Read a little-endian length of the given width for a PUSHDATA opcode
+ */
+fn read_push_len (iter: &mut VecIterator<u8>, width: uint) -> Option<uint>
+{
+  let mut rv = 0u;
+  for i in range (0, width) {
+    match iter.next() {
+      None => { return None; }
+      Some(&ch) => { rv += (ch as uint) << 8 * i; }
+    }
+  }
+  Some(rv)
+}
+
+/**
+ * Tokenize as much of a script as possible. Returns the instructions read
+ * and whether the whole script was consumed (i.e. false if some push ran
+ * off the end).
+ */
+fn parse_partial (script: &[u8]) -> (~[Instruction], bool)
+{
+  let mut rv: ~[Instruction] = ~[];
+  let mut iter = script.iter();
+
+  loop {
+    let op = match iter.next() {
+      None => { return (rv, true); }
+      Some(&op) => op
+    };
+    let len = match op {
+      0x00..0x4b => Some(op as uint),
+      0x4c => read_push_len (&mut iter, 1),
+      0x4d => read_push_len (&mut iter, 2),
+      0x4e => read_push_len (&mut iter, 4),
+      _ => { rv.push (Op(op)); continue; }
+    };
+    let len = match len {
+      None => { return (rv, false); }
+      Some(n) => n
+    };
+    let mut data: ~[u8] = ~[];
+    for _ in range (0, len) {
+      match iter.next() {
+        None => { return (rv, false); }
+        Some(&ch) => { data.push (ch); }
+      }
+    }
+    rv.push (Push(op, data));
+  }
+}
+
+/**
+ * Tokenize a script into opcodes and pushes. Fails if a push runs off
+ * the end of the script.
+ */
+pub fn parse (script: &[u8]) -> Option<~[Instruction]>
+{
+  match parse_partial (script) {
+    (rv, true) => Some(rv),
+    (_, false) => None
+  }
+}
+
+/**
+ * A push of some data, using the smallest opcode that can do it
+ */
+pub fn push_data (data: ~[u8]) -> Instruction
+{
+  let op = if data.len() <= 0x4b {
+    data.len() as u8
+  } else if data.len() <= 0xff {
+    OP_PUSHDATA1
+  } else if data.len() <= 0xffff {
+    OP_PUSHDATA2
+  } else {
+    OP_PUSHDATA4
+  };
+  Push(op, data)
+}
+
+/**
+ * Serialize a list of instructions back into a script
+ */
+pub fn serialize (instructions: &[Instruction]) -> ~[u8]
+{
+  let mut rv: ~[u8] = ~[];
+  for ins in instructions.iter() {
+    match *ins {
+      Op(op) => { rv.push (op); }
+      Push(op, ref data) => {
+        rv.push (op);
+        let len = data.len();
+        if op == OP_PUSHDATA1 {
+          rv.push (len as u8);
+        } else if op == OP_PUSHDATA2 {
+          rv.push (len as u8);
+          rv.push ((len >> 8) as u8);
+        } else if op == OP_PUSHDATA4 {
+          rv.push (len as u8);
+          rv.push ((len >> 8) as u8);
+          rv.push ((len >> 16) as u8);
+          rv.push ((len >> 24) as u8);
+        }
+        rv.push_all (*data);
+      }
+    }
+  }
+  rv
+}
+
+/**
+ * Remove all OP_CODESEPARATORs from a script, as is done to the script code
+ * before signature hashing. Scripts which don't parse are left untouched.
+ */
+pub fn remove_codeseparators (script: &[u8]) -> ~[u8]
+{
+  match parse (script) {
+    Some(ins) => {
+      let kept: ~[Instruction] = ins.move_iter().filter (|i| !is_op (i, OP_CODESEPARATOR)).collect();
+      serialize (kept)
+    }
+    None => script.to_owned()
+  }
+}
+
+/**
+ * The name of a (non-push) opcode, as used by bitcoind
+ */
+pub fn opcode_name (op: u8) -> &'static str
+{
+  match op {
+    0x00 => "OP_0",
+    0x4c => "OP_PUSHDATA1",
+    0x4d => "OP_PUSHDATA2",
+    0x4e => "OP_PUSHDATA4",
+    0x4f => "OP_1NEGATE",
+    0x50 => "OP_RESERVED",
+    0x51 => "OP_1",   0x52 => "OP_2",   0x53 => "OP_3",   0x54 => "OP_4",
+    0x55 => "OP_5",   0x56 => "OP_6",   0x57 => "OP_7",   0x58 => "OP_8",
+    0x59 => "OP_9",   0x5a => "OP_10",  0x5b => "OP_11",  0x5c => "OP_12",
+    0x5d => "OP_13",  0x5e => "OP_14",  0x5f => "OP_15",  0x60 => "OP_16",
+    /* control */
+    0x61 => "OP_NOP",
+    0x62 => "OP_VER",
+    0x63 => "OP_IF",
+    0x64 => "OP_NOTIF",
+    0x65 => "OP_VERIF",
+    0x66 => "OP_VERNOTIF",
+    0x67 => "OP_ELSE",
+    0x68 => "OP_ENDIF",
+    0x69 => "OP_VERIFY",
+    0x6a => "OP_RETURN",
+    /* stack */
+    0x6b => "OP_TOALTSTACK",
+    0x6c => "OP_FROMALTSTACK",
+    0x6d => "OP_2DROP",
+    0x6e => "OP_2DUP",
+    0x6f => "OP_3DUP",
+    0x70 => "OP_2OVER",
+    0x71 => "OP_2ROT",
+    0x72 => "OP_2SWAP",
+    0x73 => "OP_IFDUP",
+    0x74 => "OP_DEPTH",
+    0x75 => "OP_DROP",
+    0x76 => "OP_DUP",
+    0x77 => "OP_NIP",
+    0x78 => "OP_OVER",
+    0x79 => "OP_PICK",
+    0x7a => "OP_ROLL",
+    0x7b => "OP_ROT",
+    0x7c => "OP_SWAP",
+    0x7d => "OP_TUCK",
+    /* splice */
+    0x7e => "OP_CAT",
+    0x7f => "OP_SUBSTR",
+    0x80 => "OP_LEFT",
+    0x81 => "OP_RIGHT",
+    0x82 => "OP_SIZE",
+    /* bit logic */
+    0x83 => "OP_INVERT",
+    0x84 => "OP_AND",
+    0x85 => "OP_OR",
+    0x86 => "OP_XOR",
+    0x87 => "OP_EQUAL",
+    0x88 => "OP_EQUALVERIFY",
+    0x89 => "OP_RESERVED1",
+    0x8a => "OP_RESERVED2",
+    /* numeric */
+    0x8b => "OP_1ADD",
+    0x8c => "OP_1SUB",
+    0x8d => "OP_2MUL",
+    0x8e => "OP_2DIV",
+    0x8f => "OP_NEGATE",
+    0x90 => "OP_ABS",
+    0x91 => "OP_NOT",
+    0x92 => "OP_0NOTEQUAL",
+    0x93 => "OP_ADD",
+    0x94 => "OP_SUB",
+    0x95 => "OP_MUL",
+    0x96 => "OP_DIV",
+    0x97 => "OP_MOD",
+    0x98 => "OP_LSHIFT",
+    0x99 => "OP_RSHIFT",
+    0x9a => "OP_BOOLAND",
+    0x9b => "OP_BOOLOR",
+    0x9c => "OP_NUMEQUAL",
+    0x9d => "OP_NUMEQUALVERIFY",
+    0x9e => "OP_NUMNOTEQUAL",
+    0x9f => "OP_LESSTHAN",
+    0xa0 => "OP_GREATERTHAN",
+    0xa1 => "OP_LESSTHANOREQUAL",
+    0xa2 => "OP_GREATERTHANOREQUAL",
+    0xa3 => "OP_MIN",
+    0xa4 => "OP_MAX",
+    0xa5 => "OP_WITHIN",
+    /* crypto */
+    0xa6 => "OP_RIPEMD160",
+    0xa7 => "OP_SHA1",
+    0xa8 => "OP_SHA256",
+    0xa9 => "OP_HASH160",
+    0xaa => "OP_HASH256",
+    0xab => "OP_CODESEPARATOR",
+    0xac => "OP_CHECKSIG",
+    0xad => "OP_CHECKSIGVERIFY",
+    0xae => "OP_CHECKMULTISIG",
+    0xaf => "OP_CHECKMULTISIGVERIFY",
+    /* expansion */
+    0xb0 => "OP_NOP1",
+    0xb1 => "OP_CHECKLOCKTIMEVERIFY",
+    0xb2 => "OP_CHECKSEQUENCEVERIFY",
+    0xb3 => "OP_NOP4",
+    0xb4 => "OP_NOP5",
+    0xb5 => "OP_NOP6",
+    0xb6 => "OP_NOP7",
+    0xb7 => "OP_NOP8",
+    0xb8 => "OP_NOP9",
+    0xb9 => "OP_NOP10",
+    0xba => "OP_CHECKSIGADD",
+    _ => "OP_UNKNOWN"
+  }
+}
+
+/**
+ * Render a script as human-readable ASM. Pushes are shown as hex; if a push
+ * runs off the end of the script, the output ends with "[error]".
+ */
+pub fn to_asm (script: &[u8]) -> ~str
+{
+  let (instructions, complete) = parse_partial (script);
+  let mut words: ~[~str] = ~[];
+
+  for ins in instructions.iter() {
+    match *ins {
+      Op(op) => { words.push (opcode_name (op).to_owned()); }
+      Push(_, ref data) if data.len() == 0 => { words.push (~"OP_0"); }
+      Push(_, ref data) => { words.push (util::u8_to_hex_string (*data)); }
+    }
+  }
+  if !complete {
+    words.push (~"[error]");
+  }
+  words.connect (" ")
+}
+
+/**
+ * Whether some pushed data is a DER-encoded signature followed by a hash
+ * type byte, per the BIP66 strict encoding rules
+ */
+pub fn is_signature (sig: &[u8]) -> bool
+{
+  /* 0x30 [total-len] 0x02 [R-len] [R] 0x02 [S-len] [S] [hashtype] */
+  if sig.len() < 9 || sig.len() > 73 { return false; }
+  if sig[0] != 0x30 { return false; }
+  if sig[1] as uint != sig.len() - 3 { return false; }
+
+  let len_r = sig[3] as uint;
+  if 5 + len_r >= sig.len() { return false; }
+  let len_s = sig[5 + len_r] as uint;
+  if len_r + len_s + 7 != sig.len() { return false; }
+
+  /* R must be a positive, minimally-encoded integer */
+  if sig[2] != 0x02 || len_r == 0 { return false; }
+  if sig[4] & 0x80 != 0 { return false; }
+  if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 { return false; }
+
+  /* Likewise S */
+  if sig[len_r + 4] != 0x02 || len_s == 0 { return false; }
+  if sig[len_r + 6] & 0x80 != 0 { return false; }
+  if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 { return false; }
+
+  true
+}
+
+/**
+ * If some data is a BIP340 Schnorr signature as found in a taproot witness,
+ * return its hash type. 64-byte signatures use SIGHASH_DEFAULT (0x00); 65-byte
+ * ones give the hash type explicitly, and may not use 0x00 to do it.
+ */
+pub fn schnorr_signature_hash_type (sig: &[u8]) -> Option<u8>
+{
+  if sig.len() == 64 {
+    return Some(0x00);
+  }
+  if sig.len() == 65 {
+    match sig[64] {
+      0x01 | 0x02 | 0x03 | 0x81 | 0x82 | 0x83 => { return Some(sig[64]); }
+      _ => {}
+    }
+  }
+  None
+}
+
+/**
+ * If the instruction is a push of a signature, return its hash type
+ */
+pub fn signature_hash_type (ins: &Instruction) -> Option<u8>
+{
+  match *ins {
+    Push(_, ref data) if is_signature (*data) => Some(data[data.len() - 1]),
+    _ => None
+  }
+}
+
+/**
+ * Standard output script templates
+ */
+#[deriving(Eq, Clone)]
+pub enum ScriptType {
+  PayToPubkey,
+  PayToPubkeyHash,
+  PayToScriptHash,
+  PayToWitnessPubkeyHash,
+  PayToWitnessScriptHash,
+  PayToTaproot,
+  BareMultisig,
+  NullData,
+  Nonstandard
+}
+
+impl ScriptType {
+  /** Short name for reports */
+  pub fn name (&self) -> &'static str {
+    match *self {
+      PayToPubkey => "p2pk",
+      PayToPubkeyHash => "p2pkh",
+      PayToScriptHash => "p2sh",
+      PayToWitnessPubkeyHash => "p2wpkh",
+      PayToWitnessScriptHash => "p2wsh",
+      PayToTaproot => "p2tr",
+      BareMultisig => "multisig",
+      NullData => "nulldata",
+      Nonstandard => "nonstandard"
+    }
+  }
+}
+
+/** Whether an instruction is exactly the given opcode */
+fn is_op (ins: &Instruction, expected: u8) -> bool
+{
+  match *ins {
+    Op(op) => op == expected,
+    _ => false
+  }
+}
+
+/** If an instruction is a minimal direct push of `len` bytes, return the data */
+fn direct_push<'a> (ins: &'a Instruction, len: uint) -> Option<&'a [u8]>
+{
+  match *ins {
+    Push(op, ref data) if op as uint == len && data.len() == len => Some(data.as_slice()),
+    _ => None
+  }
+}
+
+/** Whether some data looks like a compressed or uncompressed public key */
+pub fn is_pubkey (data: &[u8]) -> bool
+{
+  (data.len() == 33 && (data[0] == 0x02 || data[0] == 0x03)) ||
+  (data.len() == 65 && data[0] == 0x04)
+}
+
+/** The value of a small-integer opcode OP_1 through OP_16 */
+fn small_int (ins: &Instruction) -> Option<uint>
+{
+  match *ins {
+    Op(op) if op >= OP_1 && op <= OP_16 => Some((op - OP_1 + 1) as uint),
+    _ => None
+  }
+}
+
+/**
+ * If the script is a segwit output, return its version and program
+ */
+pub fn witness_program (script: &[u8]) -> Option<(u8, ~[u8])>
+{
+  /* A version opcode followed by a single 2-to-40 byte direct push */
+  if script.len() < 4 || script.len() > 42 { return None; }
+  if script[1] as uint != script.len() - 2 { return None; }
+  let version = if script[0] == OP_0 {
+    0
+  } else if script[0] >= OP_1 && script[0] <= OP_16 {
+    script[0] - OP_1 + 1
+  } else {
+    return None;
+  };
+  Some((version, script.slice_from (2).to_owned()))
+}
+
+/**
+ * If the script is an m-of-n CHECKMULTISIG script, return m and the keys
+ */
+pub fn parse_multisig (script: &[u8]) -> Option<(uint, ~[~[u8]])>
+{
+  let ins = match parse (script) {
+    Some(ins) => ins,
+    None => { return None; }
+  };
+  if ins.len() < 4 || !is_op (&ins[ins.len() - 1], OP_CHECKMULTISIG) {
+    return None;
+  }
+  let (m, n) = match (small_int (&ins[0]), small_int (&ins[ins.len() - 2])) {
+    (Some(m), Some(n)) => (m, n),
+    _ => { return None; }
+  };
+  if m > n || n != ins.len() - 3 { return None; }
+
+  let mut keys: ~[~[u8]] = ~[];
+  for key in ins.slice (1, ins.len() - 2).iter() {
+    match *key {
+      Push(_, ref data) if is_pubkey (*data) => { keys.push (data.clone()); }
+      _ => { return None; }
+    }
+  }
+  Some((m, keys))
+}
+
+/**
+ * Classify an output script
+ */
+pub fn classify (script: &[u8]) -> ScriptType
+{
+  match witness_program (script) {
+    Some((0, ref prog)) if prog.len() == 20 => { return PayToWitnessPubkeyHash; }
+    Some((0, ref prog)) if prog.len() == 32 => { return PayToWitnessScriptHash; }
+    Some((1, ref prog)) if prog.len() == 32 => { return PayToTaproot; }
+    Some(_) => { return Nonstandard; }
+    None => {}
+  }
+  if parse_multisig (script).is_some() {
+    return BareMultisig;
+  }
+
+  let ins = match parse (script) {
+    Some(ins) => ins,
+    None => { return Nonstandard; }
+  };
+  if ins.len() > 0 && is_op (&ins[0], OP_RETURN) {
+    /* OP_RETURN followed only by pushes */
+    let all_pushes = ins.slice_from (1).iter().all (|i| match *i { Push(_, _) => true, _ => false });
+    return if all_pushes { NullData } else { Nonstandard };
+  }
+  if ins.len() == 2 && is_op (&ins[1], OP_CHECKSIG) {
+    match ins[0] {
+      Push(_, ref data) if is_pubkey (*data) => { return PayToPubkey; }
+      _ => {}
+    }
+  }
+  if ins.len() == 3 && is_op (&ins[0], OP_HASH160) &&
+     direct_push (&ins[1], 20).is_some() && is_op (&ins[2], OP_EQUAL) {
+    return PayToScriptHash;
+  }
+  if ins.len() == 5 && is_op (&ins[0], OP_DUP) && is_op (&ins[1], OP_HASH160) &&
+     direct_push (&ins[2], 20).is_some() &&
+     is_op (&ins[3], OP_EQUALVERIFY) && is_op (&ins[4], OP_CHECKSIG) {
+    return PayToPubkeyHash;
+  }
+  Nonstandard
+}
+
+
+
+#[cfg(test)]
+mod tests {
+  use std::vec;
+
+  use script::{Op, Push, OP_PUSHDATA1, OP_PUSHDATA2, OP_PUSHDATA4, OP_CHECKSIG,
+               parse, serialize, push_data, to_asm, is_signature, remove_codeseparators};
+  use script::{ScriptType, PayToPubkey, PayToPubkeyHash, PayToScriptHash, PayToWitnessPubkeyHash,
+               PayToWitnessScriptHash, PayToTaproot, BareMultisig, NullData, Nonstandard, classify};
+  use util::{hex_string_to_u8, u8_to_hex_string};
+
+  fn round_trip (hex: &str) -> ~str {
+    u8_to_hex_string (serialize (parse (hex_string_to_u8 (hex).unwrap()).unwrap()))
+  }
+
+  fn parses (hex: &str) -> bool {
+    parse (hex_string_to_u8 (hex).unwrap()).is_some()
+  }
+
+  #[test]
+  fn pushdata_round_trips() {
+    /* Direct pushes, and each PUSHDATA width, including non-minimal ones,
+     * come back exactly as they were */
+    let scripts = [
+      "", "00", "76a914b69a83e41dc486f52e9d4b4bc42e57dae2c7210288ac",
+      "4c02aabb", "4d0300aabbcc", "4e01000000aa",
+      "4c00ac", "4d0000", "4e00000000"
+    ];
+    for &hex in scripts.iter() {
+      assert_eq! (round_trip (hex), hex.to_owned());
+    }
+    let ins = parse (hex_string_to_u8 ("4d0300aabbccac").unwrap()).unwrap();
+    assert_eq! (ins.len(), 2);
+    match ins[0] {
+      Push(op, ref data) => { assert_eq! (op, OP_PUSHDATA2); assert_eq! (data.len(), 3); }
+      Op(_) => { fail! ("PUSHDATA2 parsed as an opcode"); }
+    }
+    match ins[1] {
+      Op(op) => { assert_eq! (op, OP_CHECKSIG); }
+      Push(_, _) => { fail! ("OP_CHECKSIG parsed as a push"); }
+    }
+
+    /* push_data uses the smallest opcode that will do */
+    for &(len, ref prefix) in [(75u, ~[75u8]), (76, ~[OP_PUSHDATA1, 76]),
+                           (256, ~[OP_PUSHDATA2, 0, 1]), (65536, ~[OP_PUSHDATA4, 0, 0, 1, 0])].iter() {
+      let script = serialize ([push_data (vec::from_elem (len, 0xaau8))]);
+      assert_eq! (script.len(), prefix.len() + len);
+      assert_eq! (script.slice_to (prefix.len()).to_owned(), prefix.clone());
+      assert_eq! (serialize (parse (script).unwrap()), script);
+    }
+  }
+
+  #[test]
+  fn truncated_pushes() {
+    let scripts = ["01", "03aabb", "4c", "4c05aabb", "4d01", "4d0100", "4e010000", "4e01000000", "76a914aabb"];
+    for &hex in scripts.iter() {
+      assert! (!parses (hex));
+    }
+  }
+
+  #[test]
+  fn asm() {
+    let vectors = [
+      ("76a914b69a83e41dc486f52e9d4b4bc42e57dae2c7210288ac",
+       "OP_DUP OP_HASH160 b69a83e41dc486f52e9d4b4bc42e57dae2c72102 OP_EQUALVERIFY OP_CHECKSIG"),
+      ("0014751e76e8199196d454941c45d1b3a323f1433bd6", "OP_0 751e76e8199196d454941c45d1b3a323f1433bd6"),
+      ("4c02aabb4d0100cc", "aabb cc"),
+      ("5160ab4f", "OP_1 OP_16 OP_CODESEPARATOR OP_1NEGATE"),
+      ("b1b2baff", "OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY OP_CHECKSIGADD OP_UNKNOWN"),
+      /* a push off the end */
+      ("6a03aabb", "OP_RETURN [error]"),
+      ("", "")
+    ];
+    for &(hex, asm) in vectors.iter() {
+      assert_eq! (to_asm (hex_string_to_u8 (hex).unwrap()), asm.to_owned());
+    }
+  }
+
+  #[test]
+  fn bip66_signatures() {
+    let vectors = [
+      ("300602010102010101", true),
+      /* zero padding is only allowed to keep R or S positive */
+      ("30070202008002010101", true),
+      ("30070201010202008001", true),
+      ("30070202000102010101", false),
+      ("30070201010202000101", false),
+      /* negative R or S */
+      ("300602018102010101", false),
+      ("300602010102018101", false),
+      /* wrong lengths or markers */
+      ("300702010102010101", false),
+      ("300602010102020101", false),
+      ("310602010102010101", false),
+      ("300603010102010101", false),
+      ("3006020101020101", false)
+    ];
+    for &(hex, valid) in vectors.iter() {
+      assert_eq! (is_signature (hex_string_to_u8 (hex).unwrap()), valid);
+    }
+    /* empty R, and too long */
+    assert! (!is_signature (hex_string_to_u8 ("300602000202010101").unwrap()));
+    assert! (!is_signature (vec::from_elem (74, 0x30u8)));
+  }
+
+  #[test]
+  fn codeseparators() {
+    let vectors = [
+      ("ab76abab", "76"),
+      /* 0xab inside a push is data, not an opcode */
+      ("01abab", "01ab"),
+      ("4c01abac", "4c01abac"),
+      /* scripts which don't parse are left alone */
+      ("ab4c", "ab4c")
+    ];
+    for &(hex, expected) in vectors.iter() {
+      assert_eq! (u8_to_hex_string (remove_codeseparators (hex_string_to_u8 (hex).unwrap())), expected.to_owned());
+    }
+  }
+
+  #[test]
+  fn classification() {
+    let vectors: ~[(&str, ScriptType)] = ~[
+      ("2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9fac", PayToPubkey),
+      ("410479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8ac",
+       PayToPubkey),
+      ("76a914b69a83e41dc486f52e9d4b4bc42e57dae2c7210288ac", PayToPubkeyHash),
+      ("a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87", PayToScriptHash),
+      ("0014751e76e8199196d454941c45d1b3a323f1433bd6", PayToWitnessPubkeyHash),
+      ("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262", PayToWitnessScriptHash),
+      ("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", PayToTaproot),
+      ("51210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f52ae",
+       BareMultisig),
+      ("6a04deadbeef", NullData),
+      ("6a", NullData),
+      /* an opcode after OP_RETURN */
+      ("6a51", Nonstandard),
+      /* witness programs of unknown versions or lengths */
+      ("5210751e76e8199196d454941c45d1b3a323", Nonstandard),
+      ("0010751e76e8199196d454941c45d1b3a323", Nonstandard),
+      /* P2PKH with a non-minimal push of the hash */
+      ("76a94c14b69a83e41dc486f52e9d4b4bc42e57dae2c7210288ac", Nonstandard),
+      /* P2PK with something that isn't a key */
+      ("2105c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9fac", Nonstandard),
+      /* more keys than the script says */
+      ("51210330a41094a8ad2950b1eba543a81f2d19198014c406e95a565a8477948053b58d2103c77c504a6a1c6bf5c719e77696ddfe4707882640d1d66e1c7633ef62c1cd1c9f51ae",
+       Nonstandard),
+      ("51", Nonstandard),
+      ("", Nonstandard),
+      ("4c", Nonstandard)
+    ];
+    for &(hex, ty) in vectors.iter() {
+      assert_eq! (classify (hex_string_to_u8 (hex).unwrap()), ty);
+    }
+    assert_eq! (PayToWitnessPubkeyHash.name(), "p2wpkh");
+    assert_eq! (NullData.name(), "nulldata");
+  }
+}
@@ -0,0 +1,419 @@
+
+/**
+ * Just enough secp256k1 to verify ECDSA signatures. Numbers are 256-bit,
+ * stored as eight little-endian 32-bit limbs. Nothing here is constant-time,
+ * which is fine since we only ever handle public data.
+ */
+
+type Limbs = [u32, ..8];
+
+static ZERO: Limbs = [0, 0, 0, 0, 0, 0, 0, 0];
+static ONE: Limbs = [1, 0, 0, 0, 0, 0, 0, 0];
+static TWO: Limbs = [2, 0, 0, 0, 0, 0, 0, 0];
+static SEVEN: Limbs = [7, 0, 0, 0, 0, 0, 0, 0];
+
+/**
+ * A modulus of the form 2^256 - c, which both the field size and the group
+ * order are. This lets us reduce a 512-bit hi:lo as hi * c + lo.
+ */
+struct Modulus {
+  m: Limbs,
+  c: Limbs
+}
+
+/* The field size p */
+static FIELD: Modulus = Modulus {
+  m: [0xfffffc2f, 0xfffffffe, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff],
+  c: [0x000003d1, 0x00000001, 0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000]
+};
+
+/* The group order n */
+static ORDER: Modulus = Modulus {
+  m: [0xd0364141, 0xbfd25e8c, 0xaf48a03b, 0xbaaedce6, 0xfffffffe, 0xffffffff, 0xffffffff, 0xffffffff],
+  c: [0x2fc9bebf, 0x402da173, 0x50b75fc4, 0x45512319, 0x00000001, 0x00000000, 0x00000000, 0x00000000]
+};
+
+/* (p + 1) / 4, for square roots mod p */
+static SQRT_EXPONENT: Limbs = [0xbfffff0c, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0x3fffffff];
+
+/* The generator */
+static GX: Limbs = [0x16f81798, 0x59f2815b, 0x2dce28d9, 0x029bfcdb, 0xce870b07, 0x55a06295, 0xf9dcbbac, 0x79be667e];
+static GY: Limbs = [0xfb10d4b8, 0x9c47d08f, 0xa6855419, 0xfd17b448, 0x0e1108a8, 0x5da4fbfc, 0x26a3c465, 0x483ada77];
+
+
+/* MULTIPRECISION ARITHMETIC */
+
+fn from_be (bytes: &[u8]) -> Limbs
+{
+  let mut rv = ZERO;
+  for i in range (0u, 8) {
+    let b = bytes.slice (28 - 4 * i, 32 - 4 * i);
+    rv[i] = ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32);
+  }
+  rv
+}
+
+fn is_zero (a: &Limbs) -> bool
+{
+  a.iter().all (|&l| l == 0)
+}
+
+fn equal (a: &Limbs, b: &Limbs) -> bool
+{
+  a.iter().zip (b.iter()).all (|(x, y)| x == y)
+}
+
+/** a >= b */
+fn geq (a: &Limbs, b: &Limbs) -> bool
+{
+  for i in range (0u, 8).invert() {
+    if a[i] != b[i] { return a[i] > b[i]; }
+  }
+  true
+}
+
+fn bit (a: &Limbs, n: uint) -> bool
+{
+  (a[n / 32] >> (n % 32)) & 1 == 1
+}
+
+/** a + b, and the carry out */
+fn add_limbs (a: &Limbs, b: &Limbs) -> (Limbs, u32)
+{
+  let mut rv = ZERO;
+  let mut carry = 0u64;
+  for i in range (0u, 8) {
+    let t = (a[i] as u64) + (b[i] as u64) + carry;
+    rv[i] = t as u32;
+    carry = t >> 32;
+  }
+  (rv, carry as u32)
+}
+
+/** a - b, and the borrow out */
+fn sub_limbs (a: &Limbs, b: &Limbs) -> (Limbs, u32)
+{
+  let mut rv = ZERO;
+  let mut borrow = 0u64;
+  for i in range (0u, 8) {
+    let t = (a[i] as u64) - (b[i] as u64) - borrow;
+    rv[i] = t as u32;
+    borrow = (t >> 32) & 1;
+  }
+  (rv, borrow as u32)
+}
+
+/** Full 512-bit product */
+fn mul_wide (a: &Limbs, b: &Limbs) -> [u32, ..16]
+{
+  let mut rv = [0u32, ..16];
+  for i in range (0u, 8) {
+    let mut carry = 0u64;
+    for j in range (0u, 8) {
+      let t = (a[i] as u64) * (b[j] as u64) + (rv[i + j] as u64) + carry;
+      rv[i + j] = t as u32;
+      carry = t >> 32;
+    }
+    rv[i + 8] = carry as u32;
+  }
+  rv
+}
+
+impl Modulus {
+  /** Reduce a number less than 2^256 */
+  fn reduce (&self, a: &Limbs) -> Limbs {
+    let mut rv = *a;
+    while geq (&rv, &self.m) {
+      let (d, _) = sub_limbs (&rv, &self.m);
+      rv = d;
+    }
+    rv
+  }
+
+  /** Reduce a 512-bit number */
+  fn reduce_wide (&self, a: &[u32, ..16]) -> Limbs {
+    let mut x = *a;
+    while !x.slice_from (8).iter().all (|&l| l == 0) {
+      let mut hi = ZERO;
+      for i in range (0u, 8) { hi[i] = x[i + 8]; }
+      let t = mul_wide (&hi, &self.c);
+      let mut carry = 0u64;
+      for i in range (0u, 16) {
+        let lo = if i < 8 { x[i] as u64 } else { 0 };
+        let s = (t[i] as u64) + lo + carry;
+        x[i] = s as u32;
+        carry = s >> 32;
+      }
+    }
+    let mut rv = ZERO;
+    for i in range (0u, 8) { rv[i] = x[i]; }
+    self.reduce (&rv)
+  }
+
+  fn add (&self, a: &Limbs, b: &Limbs) -> Limbs {
+    let (s, carry) = add_limbs (a, b);
+    if carry != 0 || geq (&s, &self.m) {
+      let (d, _) = sub_limbs (&s, &self.m);
+      d
+    } else {
+      s
+    }
+  }
+
+  fn sub (&self, a: &Limbs, b: &Limbs) -> Limbs {
+    let (d, borrow) = sub_limbs (a, b);
+    if borrow != 0 {
+      let (s, _) = add_limbs (&d, &self.m);
+      s
+    } else {
+      d
+    }
+  }
+
+  fn mul (&self, a: &Limbs, b: &Limbs) -> Limbs {
+    self.reduce_wide (&mul_wide (a, b))
+  }
+
+  fn pow (&self, a: &Limbs, e: &Limbs) -> Limbs {
+    let mut rv = ONE;
+    for n in range (0u, 256).invert() {
+      rv = self.mul (&rv, &rv);
+      if bit (e, n) {
+        rv = self.mul (&rv, a);
+      }
+    }
+    rv
+  }
+
+  /** Inverse by Fermat's little theorem; both moduli are prime */
+  fn inv (&self, a: &Limbs) -> Limbs {
+    let (e, _) = sub_limbs (&self.m, &TWO);
+    self.pow (a, &e)
+  }
+}
+
+
+/* CURVE ARITHMETIC (Jacobian coordinates, y^2 = x^3 + 7) */
+
+struct Point {
+  x: Limbs,
+  y: Limbs,
+  z: Limbs  /* zero for the point at infinity */
+}
+
+static INFINITY: Point = Point { x: ONE, y: ONE, z: ZERO };
+
+fn double (p: &Point) -> Point
+{
+  let f = &FIELD;
+  if is_zero (&p.z) || is_zero (&p.y) { return INFINITY; }
+
+  let a = f.mul (&p.x, &p.x);
+  let b = f.mul (&p.y, &p.y);
+  let c = f.mul (&b, &b);
+  let xb = f.add (&p.x, &b);
+  let d = f.sub (&f.sub (&f.mul (&xb, &xb), &a), &c);
+  let d = f.add (&d, &d);
+  let e = f.add (&f.add (&a, &a), &a);
+  let e2 = f.mul (&e, &e);
+  let x3 = f.sub (&e2, &f.add (&d, &d));
+  let c2 = f.add (&c, &c);
+  let c4 = f.add (&c2, &c2);
+  let c8 = f.add (&c4, &c4);
+  let y3 = f.sub (&f.mul (&e, &f.sub (&d, &x3)), &c8);
+  let z3 = f.mul (&f.add (&p.y, &p.y), &p.z);
+  Point { x: x3, y: y3, z: z3 }
+}
+
+fn add (p: &Point, q: &Point) -> Point
+{
+  let f = &FIELD;
+  if is_zero (&p.z) { return *q; }
+  if is_zero (&q.z) { return *p; }
+
+  let z1z1 = f.mul (&p.z, &p.z);
+  let z2z2 = f.mul (&q.z, &q.z);
+  let u1 = f.mul (&p.x, &z2z2);
+  let u2 = f.mul (&q.x, &z1z1);
+  let s1 = f.mul (&f.mul (&p.y, &q.z), &z2z2);
+  let s2 = f.mul (&f.mul (&q.y, &p.z), &z1z1);
+  let h = f.sub (&u2, &u1);
+  let r = f.sub (&s2, &s1);
+  if is_zero (&h) {
+    return if is_zero (&r) { double (p) } else { INFINITY };
+  }
+
+  let h2 = f.mul (&h, &h);
+  let h3 = f.mul (&h, &h2);
+  let u1h2 = f.mul (&u1, &h2);
+  let x3 = f.sub (&f.sub (&f.mul (&r, &r), &h3), &f.add (&u1h2, &u1h2));
+  let y3 = f.sub (&f.mul (&r, &f.sub (&u1h2, &x3)), &f.mul (&s1, &h3));
+  let z3 = f.mul (&f.mul (&p.z, &q.z), &h);
+  Point { x: x3, y: y3, z: z3 }
+}
+
+/** a*P + b*Q, sharing the doublings (Shamir's trick) */
+fn double_mul (a: &Limbs, p: &Point, b: &Limbs, q: &Point) -> Point
+{
+  let pq = add (p, q);
+  let mut rv = INFINITY;
+  for n in range (0u, 256).invert() {
+    rv = double (&rv);
+    match (bit (a, n), bit (b, n)) {
+      (true, true) => { rv = add (&rv, &pq); }
+      (true, false) => { rv = add (&rv, p); }
+      (false, true) => { rv = add (&rv, q); }
+      (false, false) => {}
+    }
+  }
+  rv
+}
+
+/** x^3 + 7 */
+fn curve_rhs (x: &Limbs) -> Limbs
+{
+  let f = &FIELD;
+  f.add (&f.mul (&f.mul (x, x), x), &SEVEN)
+}
+
+
+/* KEYS AND SIGNATURES */
+
+/**
+ * Parse a compressed or uncompressed public key, checking it is on the curve
+ */
+fn parse_pubkey (pk: &[u8]) -> Option<Point>
+{
+  let f = &FIELD;
+  if pk.len() == 33 && (pk[0] == 0x02 || pk[0] == 0x03) {
+    let x = from_be (pk.slice (1, 33));
+    if geq (&x, &f.m) { return None; }
+    let rhs = curve_rhs (&x);
+    let mut y = f.pow (&rhs, &SQRT_EXPONENT);
+    if !equal (&f.mul (&y, &y), &rhs) { return None; }
+    if (y[0] & 1) as u8 != pk[0] & 1 {
+      y = f.sub (&ZERO, &y);
+    }
+    Some(Point { x: x, y: y, z: ONE })
+  } else if pk.len() == 65 && pk[0] == 0x04 {
+    let x = from_be (pk.slice (1, 33));
+    let y = from_be (pk.slice (33, 65));
+    if geq (&x, &f.m) || geq (&y, &f.m) { return None; }
+    if !equal (&f.mul (&y, &y), &curve_rhs (&x)) { return None; }
+    Some(Point { x: x, y: y, z: ONE })
+  } else {
+    None
+  }
+}
+
+/**
+ * Read one DER integer (0x02 len bytes) at `pos`, returning it and the
+ * position after it
+ */
+fn parse_der_integer (sig: &[u8], pos: uint) -> Option<(Limbs, uint)>
+{
+  if pos + 2 > sig.len() || sig[pos] != 0x02 { return None; }
+  let len = sig[pos + 1] as uint;
+  let end = pos + 2 + len;
+  if end > sig.len() { return None; }
+
+  let mut start = pos + 2;
+  while start < end && sig[start] == 0 { start += 1; }
+  if end - start > 32 { return None; }
+  let mut bytes = ::std::vec::from_elem (32 - (end - start), 0u8);
+  bytes.push_all (sig.slice (start, end));
+  Some((from_be (bytes), end))
+}
+
+/**
+ * Parse a DER signature (without hash type byte) into r and s
+ */
+fn parse_der (sig: &[u8]) -> Option<(Limbs, Limbs)>
+{
+  if sig.len() < 8 || sig[0] != 0x30 || sig[1] as uint != sig.len() - 2 { return None; }
+  match parse_der_integer (sig, 2) {
+    Some((r, pos)) => {
+      match parse_der_integer (sig, pos) {
+        Some((s, end)) if end == sig.len() => Some((r, s)),
+        _ => None
+      }
+    }
+    None => None
+  }
+}
+
+/**
+ * Verify a DER-encoded ECDSA signature (without its hash type byte) on a
+ * 32-byte message hash against a serialized public key
+ */
+pub fn verify (msg_hash: &[u8], sig: &[u8], pubkey: &[u8]) -> bool
+{
+  let n = &ORDER;
+  if msg_hash.len() != 32 { return false; }
+  let (r, s) = match parse_der (sig) {
+    Some(rs) => rs,
+    None => { return false; }
+  };
+  let q = match parse_pubkey (pubkey) {
+    Some(q) => q,
+    None => { return false; }
+  };
+  if is_zero (&r) || is_zero (&s) || geq (&r, &n.m) || geq (&s, &n.m) {
+    return false;
+  }
+
+  let z = n.reduce (&from_be (msg_hash));
+  let w = n.inv (&s);
+  let u1 = n.mul (&z, &w);
+  let u2 = n.mul (&r, &w);
+  let g = Point { x: GX, y: GY, z: ONE };
+  let pt = double_mul (&u1, &g, &u2, &q);
+  if is_zero (&pt.z) { return false; }
+
+  /* Convert x to affine and compare with r, mod n */
+  let f = &FIELD;
+  let zinv = f.inv (&pt.z);
+  let x = f.mul (&pt.x, &f.mul (&zinv, &zinv));
+  equal (&n.reduce (&x), &r)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use secp256k1::verify;
+  use util::hex_string_to_u8;
+
+  fn check (msg: &str, sig: &str, pubkey: &str) -> bool {
+    verify (hex_string_to_u8 (msg).unwrap(), hex_string_to_u8 (sig).unwrap(), hex_string_to_u8 (pubkey).unwrap())
+  }
+
+  #[test]
+  fn valid_signatures() {
+    /* sha256 of the single byte 0x00 */
+    let msg = "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d";
+    let sig = "3046022100d2020fd50dab8bcff20124c0c6d05b9bd755da32f79d635b76827eae95f109be\
+               022100d249b1d4e347d7a4431202d4942cd92d9ab1c9c2fce98ff5c8ea119ff151c772";
+    assert! (check (msg, sig, "03d7da18e28d6463ea9b7e93402aec0e122b76a669e04ad12f5f4b913f772751ef"));
+    assert! (check (msg, sig, "04d7da18e28d6463ea9b7e93402aec0e122b76a669e04ad12f5f4b913f772751ef\
+                                30b2258b85b729c8df9dda0cd1a5aa20ead6e416644fc6e75fbc327be89ead17"));
+    /* short r */
+    assert! (check ("4bf5122f344554c53bde2ebb8cd2b7e3d1600ad631c385a5d7cce23c7785459a",
+                    "3045022060610a3187a61af4ccf334c15f8d27acef40ca615f0744a6d829105f1c6177df\
+                     022100f171889f6b76f32f9a711f3dbebf6aff702907f6cc6b4555ebc19a887a92a63c",
+                    "020e04eaaddfd3334fcf258e721a7d9dd8dd3c9f586af9fb85d15326d6ce9e5640"));
+  }
+
+  #[test]
+  fn invalid_signatures() {
+    let sig = "3046022100d2020fd50dab8bcff20124c0c6d05b9bd755da32f79d635b76827eae95f109be\
+               022100d249b1d4e347d7a4431202d4942cd92d9ab1c9c2fce98ff5c8ea119ff151c772";
+    let pubkey = "03d7da18e28d6463ea9b7e93402aec0e122b76a669e04ad12f5f4b913f772751ef";
+    /* wrong message */
+    assert! (!check ("6f340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d", sig, pubkey));
+    /* wrong key parity */
+    assert! (!check ("6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d", sig,
+                     "02d7da18e28d6463ea9b7e93402aec0e122b76a669e04ad12f5f4b913f772751ef"));
+    /* garbage DER */
+    assert! (!check ("6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d", "3000", pubkey));
+  }
+}
@@ -0,0 +1,112 @@
+
+use std::rand;
+use std::rand::Rng;
+
+use hash;
+
+/**
+ * Seeds for the shuffle of the merged transaction.
+ *
+ * So that nobody has to trust the coordinator's shuffle, each participant
+ * may pick a random nonce and publish its commitment, sha256(nonce), ahead
+ * of time, then reveal the nonce with their submission. The seed is the
+ * double-SHA256 of all revealed nonces, concatenated in order of their
+ * commitments, so it can't be known (or chosen) until every nonce is in.
+ *
+ * Everything is drawn from the stream of bytes
+ *   sha256(seed || 0) || sha256(seed || 1) || ...
+ * (counters as 8-byte little-endian) read as little-endian u64s. A number
+ * below n is drawn by rejection sampling: draws at or above the largest
+ * multiple of n that fits in a u64 are discarded, the rest reduced mod n.
+ *
+ * Starting from the inputs and outputs in BIP69 order, the merge (see
+ * merge_unsigned.rs) first pins each SINGLE|ANYONECANPAY input, taken in
+ * that order, to the first unused output equal to the one it signs. A
+ * legacy input goes at its index in its submission. A segwit input goes
+ * at the k-th lowest free index below min(#inputs, #outputs), where k is
+ * drawn below the number of such free indices -- from the same stream,
+ * before anything else. Then the remaining inputs, and after them the
+ * remaining outputs, are shuffled by Fisher-Yates (for i from the last
+ * index down to 1, swap i with a draw below i + 1) and fill the free
+ * places in order.
+ */
+
+/** The commitment to a nonce */
+pub fn commitment (nonce: &[u8]) -> ~[u8]
+{
+  hash::sha256_sum (nonce)
+}
+
+/** The seed derived from a set of revealed nonces */
+pub fn from_nonces (nonces: &[~[u8]]) -> ~[u8]
+{
+  let mut committed: ~[(~[u8], ~[u8])] = nonces.iter().map (|n| (commitment (*n), n.clone())).collect();
+  committed.sort_by (|&(ref c1, _), &(ref c2, _)| c1.cmp (c2));
+
+  let mut data: ~[u8] = ~[];
+  for &(_, ref nonce) in committed.iter() {
+    data.push_all (*nonce);
+  }
+  hash::sha256d_sum (data)
+}
+
+/** A seed from the system RNG, for when no nonces are given */
+pub fn random () -> ~[u8]
+{
+  rand::task_rng().gen_vec::<u8> (32)
+}
+
+/**
+ * Deterministic random number generator, as described above
+ */
+pub struct HashRng {
+  seed: ~[u8],
+  counter: u64,
+  /* Unused bytes of the current block */
+  buffer: ~[u8]
+}
+
+impl HashRng {
+  pub fn new (seed: &[u8]) -> HashRng {
+    HashRng { seed: seed.to_owned(), counter: 0, buffer: ~[] }
+  }
+
+  /** The next 64 bits of the stream */
+  pub fn next_u64 (&mut self) -> u64 {
+    if self.buffer.len() == 0 {
+      let mut data = self.seed.clone();
+      data = hash::push_u64_le (data, self.counter);
+      self.buffer = hash::sha256_sum (data);
+      self.counter += 1;
+    }
+    let mut rv = 0u64;
+    for i in range (0u, 8) {
+      rv += (self.buffer[i] as u64) << 8 * i;
+    }
+    self.buffer = self.buffer.slice_from (8).to_owned();
+    rv
+  }
+
+  /** A uniformly random number in [0, n), for n > 0 */
+  pub fn gen_below (&mut self, n: uint) -> uint {
+    let max = 0xffffffffffffffffu64;
+    let zone = max - max % (n as u64);
+    loop {
+      let x = self.next_u64();
+      if x < zone {
+        return (x % (n as u64)) as uint;
+      }
+    }
+  }
+
+  /** Fisher-Yates shuffle */
+  pub fn shuffle<T> (&mut self, values: &mut [T]) {
+    let mut i = values.len();
+    while i > 1 {
+      i -= 1;
+      let j = self.gen_below (i + 1);
+      values.swap (i, j);
+    }
+  }
+}
+
@@ -1,21 +1,36 @@
 
+use std::from_str::from_str;
 use std::hashmap::HashMap;
 use std::to_str::ToStr;
+use std::vec;
 
+use amount::Amount;
 use decoder;
+use script;
 use util;
 use hash;
 
+/* Signature hash types */
+pub static SIGHASH_DEFAULT: u8 = 0x00;  /* taproot only */
+pub static SIGHASH_ALL: u8 = 0x01;
+pub static SIGHASH_NONE: u8 = 0x02;
+pub static SIGHASH_SINGLE: u8 = 0x03;
+pub static SIGHASH_ANYONECANPAY: u8 = 0x80;
+
 pub struct TxIn {
   prev_hash: ~[u8],
   prev_index: u32,
   scriptSig: ~[u8],
   nSequence: u32,
-  nHashType: u8
+  nHashType: u8,
+  witness: ~[~[u8]],
+  /* The output being spent, if we have been told it. This is not part of
+   * the serialization, but segwit signature hashes commit to its value. */
+  prev_output: Option<TxOut>
 }
 
 pub struct TxOut {
-  nValue: u64,
+  nValue: Amount,
   scriptPubKey: ~[u8]
 }
 
@@ -29,9 +44,10 @@ pub struct Transaction {
 /**
  * Hex string parser state machine
  */
-enum ParserState {
+pub enum ParserState {
   ReadVersion,
   ReadInputCount,
+  ReadSegwitFlag,
   ReadTxinHash,
   ReadTxinIndex,
   ReadTxinScriptSigLen,
@@ -41,22 +57,113 @@ enum ParserState {
   ReadTxoutValue,
   ReadTxoutScriptLen,
   ReadTxoutScript,
+  ReadWitnessItemCount,
+  ReadWitnessItemLen,
+  ReadWitnessItem,
   ReadLockTime,
-  Error,
   Done
 }
 
+impl ParserState {
+  /** Human-readable name of the field this state reads */
+  pub fn field_name (&self) -> &'static str {
+    match *self {
+      ReadVersion => "version",
+      ReadInputCount => "input count",
+      ReadSegwitFlag => "segwit flag",
+      ReadTxinHash => "txin hash",
+      ReadTxinIndex => "txin index",
+      ReadTxinScriptSigLen => "scriptSig length",
+      ReadTxinScriptSig => "scriptSig",
+      ReadTxinSequence => "txin sequence",
+      ReadOutputCount => "output count",
+      ReadTxoutValue => "txout value",
+      ReadTxoutScriptLen => "scriptPubKey length",
+      ReadTxoutScript => "scriptPubKey",
+      ReadWitnessItemCount => "witness item count",
+      ReadWitnessItemLen => "witness item length",
+      ReadWitnessItem => "witness item",
+      ReadLockTime => "locktime",
+      Done => "end of transaction"
+    }
+  }
+}
+
+/**
+ * Decoding strictness. Strict decoding rejects non-minimally encoded VarInts
+ * and bytes following the transaction; lenient decoding accepts them but
+ * reports them as warnings. (Either way they are not preserved, so the txid
+ * of a leniently-decoded transaction may not be what the submitter thinks.)
+ */
+pub enum DecodeMode {
+  Strict,
+  Lenient
+}
+
+/**
+ * Decoding error: the parser state we failed in, the byte offset of the
+ * field being read, and what went wrong.
+ */
+pub struct DecodeError {
+  state: ParserState,
+  offset: uint,
+  reason: ~str
+}
+
+fn decode_error (state: ParserState, offset: uint, reason: ~str) -> DecodeError
+{
+  DecodeError { state: state, offset: offset, reason: reason }
+}
+
+fn token_error (state: ParserState, offset: uint, token: decoder::Token) -> DecodeError
+{
+  let reason = match token {
+    decoder::Truncated(wanted, got) => {
+      format! ("truncated {:s}, wanted {:u} bytes, got {:u}", state.field_name(), wanted, got)
+    }
+    decoder::NonMinimal(n) => {
+      format! ("non-canonical encoding of {:s} {:u}", state.field_name(), n)
+    }
+    _ => format! ("malformed {:s}", state.field_name())
+  };
+  decode_error (state, offset, reason)
+}
+
+/**
+ * Accept a non-minimal VarInt in lenient mode (noting a warning), otherwise
+ * pass the token through unchanged
+ */
+fn canonical_varint (token: decoder::Token, mode: DecodeMode, state: ParserState, offset: uint,
+                     warnings: &mut ~[DecodeError]) -> decoder::Token
+{
+  match (token, mode) {
+    (decoder::NonMinimal(n), Lenient) => {
+      warnings.push (token_error (state, offset, decoder::NonMinimal(n)));
+      decoder::Integer(n)
+    }
+    (t, _) => t
+  }
+}
+
+impl ToStr for DecodeError {
+  fn to_str(&self) -> ~str
+  {
+    format! ("{:s} at byte {:u} (reading {:s})", self.reason, self.offset, self.state.field_name())
+  }
+}
+
 /**
  * Constructor for empty TxIn/TxOut
  */
 fn new_blank_txin() -> TxIn
 {
-  TxIn { prev_hash: ~[], prev_index: 0, scriptSig: ~[], nSequence: 0, nHashType: 0 }
+  TxIn { prev_hash: ~[], prev_index: 0, scriptSig: ~[], nSequence: 0, nHashType: 0, witness: ~[],
+         prev_output: None }
 }
 
 fn new_blank_txout() -> TxOut
 {
-  TxOut { nValue: 0, scriptPubKey: ~[] }
+  TxOut { nValue: Amount::zero(), scriptPubKey: ~[] }
 }
 
 /**
@@ -77,7 +184,9 @@ impl Clone for TxIn {
       prev_index: self.prev_index,
       scriptSig: self.scriptSig.clone(),
       nSequence: self.nSequence,
-      nHashType: self.nHashType
+      nHashType: self.nHashType,
+      witness: self.witness.clone(),
+      prev_output: self.prev_output.clone()
     }
   }
 }
@@ -99,7 +208,16 @@ impl Clone for Transaction {
 /**
  * Constructor / createrawtransaction parser
  */
-pub fn from_hex (hex_string: &[u8]) -> Option<Transaction>
+pub fn from_hex (hex_string: &[u8]) -> Result<Transaction, DecodeError>
+{
+  from_hex_with_mode (hex_string, Strict).map (|(tx, _)| tx)
+}
+
+/**
+ * Parser with selectable strictness; returns the transaction along with
+ * any warnings (which are always empty in strict mode)
+ */
+pub fn from_hex_with_mode (hex_string: &[u8], mode: DecodeMode) -> Result<(Transaction, ~[DecodeError]), DecodeError>
 {
   let mut rv: Transaction = Transaction {
     nVersion: 0,
@@ -112,25 +230,55 @@ pub fn from_hex (hex_string: &[u8]) -> Option<Transaction>
   let mut width = 0;
   let mut vin_counter: u64 = 0;
   let mut vout_counter: u64 = 0;
+  let mut output_total = Amount::zero();
+  let mut segwit = false;
+  let mut wit_input: uint = 0;
+  let mut wit_counter: u64 = 0;
+  let mut warnings: ~[DecodeError] = ~[];
 
   /* RUN STATE MACHINE */
   let mut iter = hex_string.iter();
   let mut state = ReadVersion;  /* Initial state: read version */
   loop {
+    let (remaining, _) = iter.size_hint();
+    let offset = hex_string.len() - remaining;
     state = match state {
       /* Read big-endian u32 version */
       ReadVersion => {
         match decoder::decode_token (&mut iter, decoder::Unsigned32) {
           decoder::Integer(n) => { rv.nVersion = n as u32; ReadInputCount }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* READ INPUTS */
       ReadInputCount => {
-        match decoder::decode_token (&mut iter, decoder::VarInt) {
-          decoder::Integer(0) => { Error }  /* zero inputs is a failure */
+        match canonical_varint (decoder::decode_token (&mut iter, decoder::VarInt),
+                                mode, state, offset, &mut warnings) {
+          /* A zero input count is the BIP144 segwit marker; a second one
+           * (after the flag) means the tx really has no inputs, which is a
+           * failure. */
+          decoder::Integer(0) => {
+            if segwit {
+              return Err (decode_error (state, offset, ~"transaction has no inputs"));
+            }
+            ReadSegwitFlag
+          }
           decoder::Integer(n) => { vin_counter = n; ReadTxinHash }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
+        }
+      }
+      /* Read the segwit flag, which must be 1 */
+      ReadSegwitFlag => {
+        match decoder::decode_token (&mut iter, decoder::Bytestring(1)) {
+          decoder::String(s) => {
+            if s[0] != 1 {
+              return Err (decode_error (state, offset,
+                format! ("segwit flag must be 1, got {:u}", s[0])));
+            }
+            segwit = true;
+            ReadInputCount
+          }
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Read the hash of a txin */
@@ -142,38 +290,45 @@ pub fn from_hex (hex_string: &[u8]) -> Option<Transaction>
             rv.input.push (new_txin);
             ReadTxinIndex
           }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Read the index of a txin */
       ReadTxinIndex => {
         match decoder::decode_token (&mut iter, decoder::Unsigned32) {
           decoder::Integer(n) => { rv.input[rv.input.len() - 1].prev_index = n as u32; ReadTxinScriptSigLen }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Read the scriptSig of a txin */
       ReadTxinScriptSigLen => {
-        match decoder::decode_token (&mut iter, decoder::VarInt) {
+        match canonical_varint (decoder::decode_token (&mut iter, decoder::VarInt),
+                                mode, state, offset, &mut warnings) {
           decoder::Integer(0) => { ReadTxinSequence }  /* skip scriptSig if it has width 0 */
           decoder::Integer(n) => { width = n; ReadTxinScriptSig }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       ReadTxinScriptSig => {
         match decoder::decode_token (&mut iter, decoder::Bytestring(width)) {
           decoder::String(s) => {
-            /* A standard tx scriptSig is PUSH[n+1] followed by an n-byte signature
-             * then a 1-byte hash type. We hardcode this form since it is not clear
-             * semantically what anything except this exact form means to us --- so
-             * there's no point in doing any more intelligent processing. */
-            if s[0] > 0 && s[0] < 76 && s.len() > s[0] as uint {
-              rv.input[rv.input.len() - 1].nHashType = s[s[0]];
+            /* A standard tx scriptSig starts with a push of a signature,
+             * whose last byte is the hash type. It is not clear semantically
+             * what anything except this form means to us --- so there's no
+             * point in doing any more intelligent processing. */
+            match script::parse (s) {
+              Some(ins) if ins.len() > 0 => {
+                match script::signature_hash_type (&ins[0]) {
+                  Some(ht) => { rv.input[rv.input.len() - 1].nHashType = ht; }
+                  None => {}
+                }
+              }
+              _ => {}
             }
             rv.input[rv.input.len() - 1].scriptSig = s;
             ReadTxinSequence
           }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Read the sequence no. of a txin */
@@ -188,15 +343,19 @@ pub fn from_hex (hex_string: &[u8]) -> Option<Transaction>
               ReadOutputCount
             }
           }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* READ OUTPUTS */
       ReadOutputCount => {
-        match decoder::decode_token (&mut iter, decoder::VarInt) {
-          decoder::Integer(0) => { Error }  /* zero outputs is a failure (maybe it shouldn't be?) */
+        match canonical_varint (decoder::decode_token (&mut iter, decoder::VarInt),
+                                mode, state, offset, &mut warnings) {
+          /* zero outputs is a failure (maybe it shouldn't be?) */
+          decoder::Integer(0) => {
+            return Err (decode_error (state, offset, ~"transaction has no outputs"));
+          }
           decoder::Integer(n) => { vout_counter = n; ReadTxoutValue }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Read txout value */
@@ -204,27 +363,41 @@ pub fn from_hex (hex_string: &[u8]) -> Option<Transaction>
         match decoder::decode_token (&mut iter, decoder::Unsigned64) {
           decoder::Integer(n) => {
             let mut new_output = new_blank_txout();
-            new_output.nValue = n;
+            new_output.nValue = match Amount::from_sat (n) {
+              Some(value) => value,
+              None => {
+                return Err (decode_error (state, offset, format! ("output value {:u} exceeds MAX_MONEY", n)));
+              }
+            };
+            output_total = match output_total.checked_add (new_output.nValue) {
+              Some(total) => total,
+              None => {
+                return Err (decode_error (state, offset, ~"outputs add up to more than MAX_MONEY"));
+              }
+            };
             rv.output.push (new_output);
             ReadTxoutScriptLen
           }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Read txout script */
       ReadTxoutScriptLen => {
-        match decoder::decode_token (&mut iter, decoder::VarInt) {
+        match canonical_varint (decoder::decode_token (&mut iter, decoder::VarInt),
+                                mode, state, offset, &mut warnings) {
           /* skip scriptPubKey if it has width 0 */
           decoder::Integer(0) => {
             vout_counter -= 1;
             if vout_counter > 0 {
               ReadTxoutValue
+            } else if segwit {
+              ReadWitnessItemCount
             } else {
               ReadLockTime
             }
           }
           decoder::Integer(n) => { width = n; ReadTxoutScript }
-          _ => Error
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       ReadTxoutScript => {
@@ -234,44 +407,253 @@ pub fn from_hex (hex_string: &[u8]) -> Option<Transaction>
             vout_counter -= 1;
             if vout_counter > 0 {
               ReadTxoutValue
+            } else if segwit {
+              ReadWitnessItemCount
+            } else {
+              ReadLockTime
+            }
+          }
+          t => { return Err (token_error (state, offset, t)) }
+        }
+      }
+      /* DONE OUTPUTS, read witnesses (one stack per input) */
+      ReadWitnessItemCount => {
+        match canonical_varint (decoder::decode_token (&mut iter, decoder::VarInt),
+                                mode, state, offset, &mut warnings) {
+          /* skip the witness if it has no items */
+          decoder::Integer(0) => {
+            wit_input += 1;
+            if wit_input < rv.input.len() {
+              ReadWitnessItemCount
             } else {
               ReadLockTime
             }
           }
-          _ => Error
+          decoder::Integer(n) => { wit_counter = n; ReadWitnessItemLen }
+          t => { return Err (token_error (state, offset, t)) }
+        }
+      }
+      ReadWitnessItemLen => {
+        match canonical_varint (decoder::decode_token (&mut iter, decoder::VarInt),
+                                mode, state, offset, &mut warnings) {
+          decoder::Integer(n) => { width = n; ReadWitnessItem }
+          t => { return Err (token_error (state, offset, t)) }
+        }
+      }
+      ReadWitnessItem => {
+        match decoder::decode_token (&mut iter, decoder::Bytestring(width)) {
+          decoder::String(s) => {
+            rv.input[wit_input].witness.push (s);
+            wit_counter -= 1;
+            if wit_counter > 0 {
+              ReadWitnessItemLen
+            } else {
+              /* A P2WPKH witness is a signature followed by a pubkey, so
+               * as with scriptSigs we only pick out the hash type of this
+               * exact form. (Taproot key-path spends can't be told apart
+               * until we know the spent output; see set_prevouts.) */
+              {
+                let txin = &mut rv.input[wit_input];
+                if txin.witness.len() == 2 && script::is_signature (txin.witness[0]) {
+                  txin.nHashType = txin.witness[0][txin.witness[0].len() - 1];
+                }
+              }
+              wit_input += 1;
+              if wit_input < rv.input.len() {
+                ReadWitnessItemCount
+              } else {
+                ReadLockTime
+              }
+            }
+          }
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
-      /* DONE OUTPUTS, Read nLockTime */
+      /* DONE WITNESSES, Read nLockTime */
       ReadLockTime => {
         match decoder::decode_token (&mut iter, decoder::Unsigned32) {
-          decoder::Integer(n) => { rv.nLockTime = n as u32; Done }
-          _ => Error
+          decoder::Integer(n) => {
+            rv.nLockTime = n as u32;
+            /* BIP144 forbids the extended format when every witness is
+             * empty, since the flag would then be superfluous. */
+            if segwit && !rv.has_witness() {
+              return Err (decode_error (state, offset, ~"segwit flag set but all witnesses are empty"));
+            }
+            Done
+          }
+          t => { return Err (token_error (state, offset, t)) }
         }
       }
       /* Finished */
-      Error => { return None; }
       Done => { break }
     }
   }
 
-  Some (rv)
+  /* Check for trailing garbage */
+  let (remaining, _) = iter.size_hint();
+  if remaining > 0 {
+    let err = decode_error (Done, hex_string.len() - remaining,
+                            format! ("{:u} unexpected bytes after transaction", remaining));
+    match mode {
+      Strict => { return Err (err); }
+      Lenient => { warnings.push (err); }
+    }
+  }
+
+  Ok ((rv, warnings))
+}
+
+/**
+ * Parse a line describing a spent output, of the form
+ *   <txid>:<vout> <value in satoshis> <scriptPubKey hex>
+ * with the txid in the usual (byte-reversed) display order. Returns the
+ * outpoint, with its hash in serialization order, and the output.
+ */
+pub fn prevout_from_str (line: &str) -> Option<(~[u8], u32, TxOut)>
+{
+  let words: ~[&str] = line.words().collect();
+  if words.len() != 3 { return None; }
+  let outpoint: ~[&str] = words[0].split (':').collect();
+  if outpoint.len() != 2 { return None; }
+
+  let mut hash = match util::hex_string_to_u8 (outpoint[0]) {
+    Some(h) if h.len() == 32 => h,
+    _ => { return None; }
+  };
+  hash.reverse();
+  let value = match from_str::<u64> (words[1]) {
+    Some(sat) => Amount::from_sat (sat),
+    None => None
+  };
+  match (from_str::<u32> (outpoint[1]), value, util::hex_string_to_u8 (words[2])) {
+    (Some(index), Some(value), Some(spk)) => Some((hash, index, TxOut { nValue: value, scriptPubKey: spk })),
+    _ => None
+  }
+}
+
+/**
+ * Serialization helpers for pieces which signature hashes also commit to
+ */
+fn push_outpoint (mut buf: ~[u8], txin: &TxIn) -> ~[u8]
+{
+  buf.push_all (txin.prev_hash);
+  hash::push_u32_le (buf, txin.prev_index)
+}
+
+fn push_txout (mut buf: ~[u8], txout: &TxOut) -> ~[u8]
+{
+  buf = hash::push_u64_le (buf, txout.nValue.to_sat());
+  buf = hash::push_vi_le (buf, txout.scriptPubKey.len() as u64);
+  buf.push_all (txout.scriptPubKey);
+  buf
+}
+
+impl TxIn {
+  /**
+   * The taproot annex, if any: per BIP341, the last witness element when
+   * there are at least two and it starts with 0x50
+   */
+  pub fn taproot_annex<'a> (&'a self) -> Option<&'a [u8]> {
+    let n = self.witness.len();
+    if n >= 2 && self.witness[n - 1].len() > 0 && self.witness[n - 1][0] == 0x50 {
+      Some(self.witness[n - 1].as_slice())
+    } else {
+      None
+    }
+  }
+
+  /**
+   * If this input is a taproot key-path spend (a lone Schnorr signature,
+   * plus optional annex, spending a P2TR output), return the signature's
+   * hash type. Without the spent output we can't tell: any 64- or 65-byte
+   * witness element would look like a Schnorr signature, so we return None.
+   */
+  pub fn taproot_key_spend_hash_type (&self) -> Option<u8> {
+    match self.prev_output {
+      Some(ref out) if out.script_type() == script::PayToTaproot => {}
+      _ => { return None; }
+    }
+    let stack_len = if self.taproot_annex().is_some() { self.witness.len() - 1 } else { self.witness.len() };
+    if stack_len != 1 {
+      return None;
+    }
+    script::schnorr_signature_hash_type (self.witness[0])
+  }
+
+  /**
+   * The hash types of every signature on this input, in either the
+   * scriptSig or the witness. Unlike nHashType, which comes from the first
+   * signature only, this also sees e.g. each signature of a multisig spend.
+   */
+  pub fn signature_hash_types (&self) -> ~[u8] {
+    match self.taproot_key_spend_hash_type() {
+      Some(ht) => { return ~[ht]; }
+      None => {}
+    }
+    let mut rv: ~[u8] = ~[];
+    match script::parse (self.scriptSig) {
+      Some(ins) => {
+        for i in ins.iter() {
+          match script::signature_hash_type (i) {
+            Some(ht) => { rv.push (ht); }
+            None => {}
+          }
+        }
+      }
+      None => {}
+    }
+    for item in self.witness.iter() {
+      if script::is_signature (*item) {
+        rv.push (item[item.len() - 1]);
+      }
+    }
+    rv
+  }
+}
+
+impl TxOut {
+  /** Classify the scriptPubKey of this output */
+  pub fn script_type (&self) -> script::ScriptType {
+    script::classify (self.scriptPubKey)
+  }
 }
 
 impl Transaction {
+  /** Whether any input carries witness data */
+  pub fn has_witness (&self) -> bool {
+    self.input.iter().any (|txin| txin.witness.len() > 0)
+  }
+
 /**
- * Private serialize function
+ * Private serialize functions. `serialize` uses the BIP144 extended format
+ * whenever there is witness data; `serialize_stripped` never does, and is
+ * what the txid commits to.
  */
   fn serialize (&self) -> ~[u8]
+  {
+    self.serialize_with_witness (self.has_witness())
+  }
+
+  fn serialize_stripped (&self) -> ~[u8]
+  {
+    self.serialize_with_witness (false)
+  }
+
+  fn serialize_with_witness (&self, witness: bool) -> ~[u8]
   {
     let mut rv:~[u8] = ~[];
 
     /* push version */
     rv = hash::push_u32_le (rv, self.nVersion);
+    /* push segwit marker and flag */
+    if witness {
+      rv.push (0x00);
+      rv.push (0x01);
+    }
     /* push txins */
     rv = hash::push_vi_le (rv, self.input.len() as u64);
     for txin in self.input.iter() {
-      rv.push_all (txin.prev_hash);
-      rv = hash::push_u32_le (rv, txin.prev_index);
+      rv = push_outpoint (rv, txin);
       rv = hash::push_vi_le (rv, txin.scriptSig.len() as u64);
       rv.push_all (txin.scriptSig);
       rv = hash::push_u32_le (rv, txin.nSequence);
@@ -279,17 +661,349 @@ impl Transaction {
     /* push txouts */
     rv = hash::push_vi_le (rv, self.output.len() as u64);
     for txout in self.output.iter() {
-      rv = hash::push_u64_le (rv, txout.nValue);
-      rv = hash::push_vi_le (rv, txout.scriptPubKey.len() as u64);
-      rv.push_all (txout.scriptPubKey);
+      rv = push_txout (rv, txout);
+    }
+    /* push witnesses */
+    if witness {
+      for txin in self.input.iter() {
+        rv = hash::push_vi_le (rv, txin.witness.len() as u64);
+        for item in txin.witness.iter() {
+          rv = hash::push_vi_le (rv, item.len() as u64);
+          rv.push_all (item.as_slice());
+        }
+      }
     }
     /* push locktime */
     rv = hash::push_u32_le (rv, self.nLockTime);
     rv
   }
 
+  /**
+   * Legacy (pre-segwit) signature hash for the input at `index`, signing
+   * with the given script code and hash type. Any OP_CODESEPARATORs in the
+   * script code are removed, as bitcoind does. Out-of-range inputs, and
+   * SIGHASH_SINGLE inputs without a matching output, hash to 1, which is
+   * what bitcoind does (the "SIGHASH_SINGLE bug").
+   */
+  pub fn signature_hash (&self, index: uint, script_code: &[u8], hash_type: u8) -> ~[u8] {
+    self.signature_hash_u32 (index, script_code, hash_type as u32)
+  }
+
+  /* bitcoind takes the hash type as a 32-bit integer, and hashes all of it,
+   * though a signature only has room for the low byte; its test vectors use
+   * the rest */
+  fn signature_hash_u32 (&self, index: uint, script_code: &[u8], hash_type: u32) -> ~[u8] {
+    let mut one = vec::from_elem (32, 0u8);
+    one[0] = 1;
+
+    let base_type = (hash_type & 0x1f) as u8;
+    if index >= self.input.len() { return one; }
+    if base_type == SIGHASH_SINGLE && index >= self.output.len() { return one; }
+
+    let mut tx = self.clone();
+    /* Blank out all scriptSigs (and witnesses) except ours, which gets the
+     * script code; with NONE or SINGLE, other inputs' sequences don't count */
+    for (n, txin) in tx.input.mut_iter().enumerate() {
+      txin.witness = ~[];
+      if n == index {
+        txin.scriptSig = script::remove_codeseparators (script_code);
+      } else {
+        txin.scriptSig = ~[];
+        if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+          txin.nSequence = 0;
+        }
+      }
+    }
+    /* NONE signs no outputs; SINGLE signs only the matching one, with all
+     * earlier outputs replaced by blank ones with value -1 */
+    if base_type == SIGHASH_NONE {
+      tx.output = ~[];
+    } else if base_type == SIGHASH_SINGLE {
+      tx.output.truncate (index + 1);
+      for txout in tx.output.mut_slice_to (index).mut_iter() {
+        txout.nValue = Amount::sighash_single_blank();
+        txout.scriptPubKey = ~[];
+      }
+    }
+    /* ANYONECANPAY signs only our input */
+    if hash_type & SIGHASH_ANYONECANPAY as u32 != 0 {
+      tx.input = ~[tx.input[index].clone()];
+    }
+
+    let mut data = tx.serialize_stripped();
+    data = hash::push_u32_le (data, hash_type);
+    hash::sha256d_sum (data)
+  }
+
+  /**
+   * BIP143 signature hash for the segwit v0 input at `index`, which spends
+   * `amount` satoshis, signing with the given script code and hash type.
+   * For P2WPKH the script code is the corresponding P2PKH script; for P2WSH
+   * it is the witness script.
+   */
+  pub fn segwit_v0_signature_hash (&self, index: uint, script_code: &[u8], amount: Amount, hash_type: u8) -> ~[u8] {
+    let base_type = hash_type & 0x1f;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+    let zero = vec::from_elem (32, 0u8);
+
+    let hash_prevouts = if !anyone_can_pay {
+      let mut data: ~[u8] = ~[];
+      for txin in self.input.iter() {
+        data = push_outpoint (data, txin);
+      }
+      hash::sha256d_sum (data)
+    } else {
+      zero.clone()
+    };
+
+    let hash_sequence = if !anyone_can_pay && base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+      let mut data: ~[u8] = ~[];
+      for txin in self.input.iter() {
+        data = hash::push_u32_le (data, txin.nSequence);
+      }
+      hash::sha256d_sum (data)
+    } else {
+      zero.clone()
+    };
+
+    let hash_outputs = if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+      let mut data: ~[u8] = ~[];
+      for txout in self.output.iter() {
+        data = push_txout (data, txout);
+      }
+      hash::sha256d_sum (data)
+    } else if base_type == SIGHASH_SINGLE && index < self.output.len() {
+      hash::sha256d_sum (push_txout (~[], &self.output[index]))
+    } else {
+      zero.clone()
+    };
+
+    let txin = &self.input[index];
+    let mut data: ~[u8] = ~[];
+    data = hash::push_u32_le (data, self.nVersion);
+    data.push_all (hash_prevouts);
+    data.push_all (hash_sequence);
+    data = push_outpoint (data, txin);
+    data = hash::push_vi_le (data, script_code.len() as u64);
+    data.push_all (script_code);
+    data = hash::push_u64_le (data, amount.to_sat());
+    data = hash::push_u32_le (data, txin.nSequence);
+    data.push_all (hash_outputs);
+    data = hash::push_u32_le (data, self.nLockTime);
+    data = hash::push_u32_le (data, hash_type as u32);
+    hash::sha256d_sum (data)
+  }
+
+  /**
+   * BIP341 signature hash for a taproot key-path spend of the input at
+   * `index`. `spent` must give the outputs spent by every input, in order
+   * (only our own is used with ANYONECANPAY). Returns None for hash types
+   * BIP341 does not allow, and for SIGHASH_SINGLE without a matching output.
+   *
+   * Of the hash types, DEFAULT, ALL, NONE and SINGLE commit to every input
+   * (by outpoint, amount and scriptPubKey) so can never survive a merge;
+   * NONE|ANYONECANPAY always can; SINGLE|ANYONECANPAY can if its output
+   * keeps its index; ALL|ANYONECANPAY only if the outputs are unchanged.
+   */
+  pub fn taproot_signature_hash (&self, index: uint, spent: &[TxOut], hash_type: u8, annex: Option<&[u8]>) -> Option<~[u8]> {
+    match hash_type {
+      0x00 | 0x01 | 0x02 | 0x03 | 0x81 | 0x82 | 0x83 => {}
+      _ => { return None; }
+    }
+    let base_type = hash_type & 0x03;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+    if index >= self.input.len() || spent.len() != self.input.len() {
+      return None;
+    }
+    if base_type == SIGHASH_SINGLE && index >= self.output.len() {
+      return None;
+    }
+
+    /* Epoch, then the signature message proper */
+    let mut msg: ~[u8] = ~[0x00, hash_type];
+    msg = hash::push_u32_le (msg, self.nVersion);
+    msg = hash::push_u32_le (msg, self.nLockTime);
+    if !anyone_can_pay {
+      let mut prevouts: ~[u8] = ~[];
+      let mut amounts: ~[u8] = ~[];
+      let mut spks: ~[u8] = ~[];
+      let mut sequences: ~[u8] = ~[];
+      for (txin, out) in self.input.iter().zip (spent.iter()) {
+        prevouts = push_outpoint (prevouts, txin);
+        amounts = hash::push_u64_le (amounts, out.nValue.to_sat());
+        spks = hash::push_vi_le (spks, out.scriptPubKey.len() as u64);
+        spks.push_all (out.scriptPubKey);
+        sequences = hash::push_u32_le (sequences, txin.nSequence);
+      }
+      msg.push_all (hash::sha256_sum (prevouts));
+      msg.push_all (hash::sha256_sum (amounts));
+      msg.push_all (hash::sha256_sum (spks));
+      msg.push_all (hash::sha256_sum (sequences));
+    }
+    if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+      let mut outputs: ~[u8] = ~[];
+      for txout in self.output.iter() {
+        outputs = push_txout (outputs, txout);
+      }
+      msg.push_all (hash::sha256_sum (outputs));
+    }
+
+    /* Key-path spends have ext_flag 0, so the spend type is just the
+     * annex bit */
+    msg.push (if annex.is_some() { 1 } else { 0 });
+    let txin = &self.input[index];
+    if anyone_can_pay {
+      msg = push_outpoint (msg, txin);
+      msg = push_txout (msg, &spent[index]);
+      msg = hash::push_u32_le (msg, txin.nSequence);
+    } else {
+      msg = hash::push_u32_le (msg, index as u32);
+    }
+    match annex {
+      Some(a) => {
+        let mut data = hash::push_vi_le (~[], a.len() as u64);
+        data.push_all (a);
+        msg.push_all (hash::sha256_sum (data));
+      }
+      None => {}
+    }
+    if base_type == SIGHASH_SINGLE {
+      msg.push_all (hash::sha256_sum (push_txout (~[], &self.output[index])));
+    }
+
+    Some(hash::tagged_hash ("TapSighash", msg))
+  }
+
+  /**
+   * Fill in the spent output of every input that appears in `prevouts`
+   */
+  pub fn set_prevouts (&mut self, prevouts: &[(~[u8], u32, TxOut)]) {
+    for txin in self.input.mut_iter() {
+      for &(ref hash, index, ref out) in prevouts.iter() {
+        if txin.prev_hash == *hash && txin.prev_index == index {
+          txin.prev_output = Some(out.clone());
+        }
+      }
+      /* Now we may be able to recognize a taproot key-path spend */
+      match txin.taproot_key_spend_hash_type() {
+        Some(ht) => { txin.nHashType = ht; }
+        None => {}
+      }
+    }
+  }
+
+  /**
+   * The outputs spent by each input, as far as we know them; unknown ones
+   * are filled in with blank outputs
+   */
+  pub fn spent_outputs (&self) -> ~[TxOut] {
+    self.input.iter().map (|txin| {
+      match txin.prev_output {
+        Some(ref out) => out.clone(),
+        None => new_blank_txout()
+      }
+    }).collect()
+  }
+
+  /**
+   * The total value of the outputs spent, or None if we don't know them
+   * all (or they add up to more than MAX_MONEY, which real outputs can't)
+   */
+  pub fn input_value (&self) -> Option<Amount> {
+    let mut total = Amount::zero();
+    for txin in self.input.iter() {
+      total = match txin.prev_output {
+        Some(ref out) => match total.checked_add (out.nValue) {
+          Some(sum) => sum,
+          None => { return None; }
+        },
+        None => { return None; }
+      };
+    }
+    Some(total)
+  }
+
+  /**
+   * The total value of the outputs, or None if it is more than MAX_MONEY
+   * (which a decoded transaction can't be, but a merged one could)
+   */
+  pub fn output_value (&self) -> Option<Amount> {
+    let mut total = Amount::zero();
+    for output in self.output.iter() {
+      total = match total.checked_add (output.nValue) {
+        Some(sum) => sum,
+        None => { return None; }
+      };
+    }
+    Some(total)
+  }
+
+  /**
+   * The fee paid, if we know the value of every input and they cover
+   * the outputs
+   */
+  pub fn fee (&self) -> Option<Amount> {
+    match (self.input_value(), self.output_value()) {
+      (Some(ins), Some(outs)) => ins.checked_sub (outs),
+      _ => None
+    }
+  }
+
+  /**
+   * This function generates a wtxid for the transaction, which unlike the
+   * txid also commits to witness data. It is the same as the txid for
+   * transactions without witnesses.
+   */
+  pub fn wtxid (&self) -> ~[u8] {
+    let mut rv = hash::sha256d_sum (self.serialize());
+    rv.reverse();
+    rv
+  }
+
+  /** Size in bytes, without witness data */
+  pub fn stripped_size (&self) -> uint {
+    self.serialize_stripped().len()
+  }
+
+  /** Size in bytes, including witness data */
+  pub fn total_size (&self) -> uint {
+    self.serialize().len()
+  }
+
+  /** BIP141 weight: non-witness bytes count four times, witness bytes once */
+  pub fn weight (&self) -> uint {
+    3 * self.stripped_size() + self.total_size()
+  }
+
+  /** Virtual size: weight divided by four, rounded up */
+  pub fn vsize (&self) -> uint {
+    (self.weight() + 3) / 4
+  }
+
+  /**
+   * Count the outputs of each script type, in order of first appearance
+   */
+  pub fn output_type_counts (&self) -> ~[(script::ScriptType, uint)] {
+    let mut rv: ~[(script::ScriptType, uint)] = ~[];
+    for output in self.output.iter() {
+      let ty = output.script_type();
+      let mut already_present = false;
+      for entry in rv.mut_iter() {
+        let (t, count) = *entry;
+        if t == ty {
+          *entry = (t, count + 1);
+          already_present = true;
+        }
+      }
+      if !already_present {
+        rv.push ((ty, 1));
+      }
+    }
+    rv
+  }
+
   /** Getter for mpo */
-  pub fn most_popular_output (&self) -> u64 {
+  pub fn most_popular_output (&self) -> Amount {
     fn fold_function ((max_elem, max_count): (u64, uint), (&elem, &count): (&u64, &uint)) -> (u64, uint) {
       if count > max_count {
         (elem, count)
@@ -313,9 +1027,10 @@ impl Transaction {
     let mut values: HashMap<u64,uint> = HashMap::new ();
     /* For each output increment its count */
     for output in self.output.iter() {
-      values.mangle (output.nValue, (), |_,_| 1, |_,v,_| { *v += 1; });
+      values.mangle (output.nValue.to_sat(), (), |_,_| 1, |_,v,_| { *v += 1; });
     }
-    values.iter().fold ((0, 0), fold_function).first()
+    /* This is the value of some output (or zero), so is a valid amount */
+    Amount::from_sat (values.iter().fold ((0, 0), fold_function).first()).unwrap()
   }
 
   /** Getter for mpo count */
@@ -337,9 +1052,10 @@ impl hash::Hashable for Transaction {
    */
   fn to_hash(&self) -> ~[u8]
   {
-    /* The TXID is the SHA256^2 of the serialization. We reverse it since bitcoin
-     * treats it as a little-endian 256-bit number.  */
-    let mut rv = hash::sha256_sum (hash::sha256_sum (self.serialize()));
+    /* The TXID is the SHA256^2 of the serialization, without any witness
+     * data. We reverse it since bitcoin treats it as a little-endian 256-bit
+     * number.  */
+    let mut rv = hash::sha256d_sum (self.serialize_stripped());
     rv.reverse();
     rv
   }
@@ -353,3 +1069,172 @@ impl ToStr for Transaction {
 }
 
 
+#[cfg(test)]
+mod tests {
+  use amount::Amount;
+  use hash::Hashable;
+  use transaction::{Transaction, TxOut, from_hex};
+  use transaction::{SIGHASH_DEFAULT, SIGHASH_ALL, SIGHASH_NONE, SIGHASH_SINGLE, SIGHASH_ANYONECANPAY};
+  use util::{hex_string_to_u8, u8_to_hex_string};
+
+  fn tx_from_str (hex: &str) -> Transaction {
+    from_hex (hex_string_to_u8 (hex).unwrap()).unwrap()
+  }
+
+  fn check_legacy (tx: &str, script_code: &str, index: uint, hash_type: u32, expected: &str) {
+    let tx = tx_from_str (tx);
+    let mut hash = tx.signature_hash_u32 (index, hex_string_to_u8 (script_code).unwrap(), hash_type);
+    /* bitcoind's vectors give the hash byte-reversed, like a txid */
+    hash.reverse();
+    assert_eq! (u8_to_hex_string (hash), expected.to_owned());
+  }
+
+  /* From bitcoind's sighash.json: ALL, NONE and SINGLE, each with and
+   * without ANYONECANPAY, and a type with no base type bits at all */
+  #[test]
+  fn legacy_vectors() {
+    check_legacy ("b7877f82019c832707a60cf14fba44cfa254d787501fdd676bd58c744f6e951dbba0b3b77f0200000009ac515263ac53525300a5a36e500148f89c0500000000085265ac6a6a65acab00000000",
+                  "6563", 0, 2509858881,
+                  "cb6e4322955af12eb29613c70e1a00ddbb559c887ba844df0bcdebed736dffbd");
+    check_legacy ("e3cdbfb4014d90ae6a4401e85f7ac717adc2c035858bf6ff48979dd399d155bce1f150daea0300000002ac51a67a0d39017f6c71040000000005535200535200000000",
+                  "", 0, 2395016385,
+                  "c1c7df8206e661d593f6455db1d61a364a249407f88e99ecad05346e495b38d7");
+    check_legacy ("2f7353dd02e395b0a4d16da0f7472db618857cd3de5b9e2789232952a9b154d249102245fd030000000151617fd88f103280b85b0a198198e438e7cab1a4c92ba58409709997cc7a65a619eb9eec3c0200000003636aabffffffff0397481c0200000000045300636a0dc97803000000000009d389030000000003ac6a53134007bb",
+                  "0000536552526a", 0, 2382221122,
+                  "30c4cd4bd6b291f7e9489cc4b4440a083f93a7664ea1f93e77a9597dab8ded9c");
+    check_legacy ("32fa0b0804e6ea101e137665a041cc2350b794e59bf42d9b09088b01cde806ec1bbea077df0200000008515153650000006506a11c55904258fa418e57b88b12724b81153260d3f4c9f080439789a391ab147aabb0fa0000000007000052ac51ab510986f2a15c0d5e05d20dc876dd2dafa435276d53da7b47c393f20900e55f163b97ce0b800000000008ab526a520065636a8087df7d4d9c985fb42308fb09dce704650719140aa6050e8955fa5d2ea46b464a333f870000000009636300636a6565006affffffff01994a0d040000000002536500000000",
+                  "516563530065", 2, 4131899010,
+                  "f58637277d2bc42e18358dc55f7e87e7043f5e33f4ce1fc974e715ef0d3d1c2a");
+    check_legacy ("6f62138301436f33a00b84a26a0457ccbfc0f82403288b9cbae39986b34357cb2ff9b889b302000000045253655335a7ff6701bac9960400000000086552ab656352635200000000",
+                  "6aac51", 0, 1444414211,
+                  "502a2435fd02898d2ff3ab08a3c19078414b32ec9b73d64a944834efc9dae10c");
+    check_legacy ("d3b7421e011f4de0f1cea9ba7458bf3486bee722519efab711a963fa8c100970cf7488b7bb0200000003525352dcd61b300148be5d05000000000000000000",
+                  "535251536aac536a", 0, 2334839171,
+                  "29aa6d2d752d3310eba20442770ad345b7f6a35f96161ede5f07b33e92053e2a");
+    check_legacy ("1123e7010240310013c74e5def60d8e14dd67aedff5a57d07a24abc84d933483431b8cf8ea0300000003530051fc6775ff1a23c627a2e605dd2560e84e27f4208300071e90f4589e762ad9c9fe8d0da95e020000000465655200ffffffff04251598030000000004ab65ab639d28d90400000000096563636aacac525153474df801000000000851525165ac51006a75e23b040000000000e5bd3a4a",
+                  "6363636565", 0, 3827842848,
+                  "9cb0dd04e9fe287b112e94a1647590d27e8b164ca13c4fe70c610fd13f82c2fd");
+  }
+
+  #[test]
+  fn legacy_single_bug() {
+    /* Four inputs, one output: SIGHASH_SINGLE on input 2 hashes to 1 */
+    let tx = tx_from_str ("32fa0b0804e6ea101e137665a041cc2350b794e59bf42d9b09088b01cde806ec1bbea077df0200000008515153650000006506a11c55904258fa418e57b88b12724b81153260d3f4c9f080439789a391ab147aabb0fa0000000007000052ac51ab510986f2a15c0d5e05d20dc876dd2dafa435276d53da7b47c393f20900e55f163b97ce0b800000000008ab526a520065636a8087df7d4d9c985fb42308fb09dce704650719140aa6050e8955fa5d2ea46b464a333f870000000009636300636a6565006affffffff01994a0d040000000002536500000000");
+    assert_eq! (u8_to_hex_string (tx.signature_hash (2, [], SIGHASH_SINGLE)),
+                ~"0100000000000000000000000000000000000000000000000000000000000000");
+    assert_eq! (u8_to_hex_string (tx.signature_hash (4, [], SIGHASH_SINGLE)),
+                ~"0100000000000000000000000000000000000000000000000000000000000000");
+  }
+
+  fn check_segwit_v0 (tx: &Transaction, index: uint, script_code: &str, amount: u64, hash_type: u8, expected: &str) {
+    let hash = tx.segwit_v0_signature_hash (index, hex_string_to_u8 (script_code).unwrap(),
+                                            Amount::from_sat (amount).unwrap(), hash_type);
+    assert_eq! (u8_to_hex_string (hash), expected.to_owned());
+  }
+
+  /* From BIP143: native P2WPKH, P2SH-P2WPKH, and P2SH-P2WSH with every
+   * hash type */
+  #[test]
+  fn segwit_v0_vectors() {
+    let tx = tx_from_str ("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000");
+    check_segwit_v0 (&tx, 1, "76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac", 600000000, SIGHASH_ALL,
+                     "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670");
+
+    let tx = tx_from_str ("0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a54770100000000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000");
+    check_segwit_v0 (&tx, 0, "76a91479091972186c449eb1ded22b78e40d009bdf008988ac", 1000000000, SIGHASH_ALL,
+                     "64f3b0f4dd2bb3aa1ce8566d220cc74dda9df97d8490cc81d89d735c92e59fb6");
+
+    let tx = tx_from_str ("010000000136641869ca081e70f394c6948e8af409e18b619df2ed74aa106c1ca29787b96e0100000000ffffffff0200e9a435000000001976a914389ffce9cd9ae88dcc0631e88a821ffdbe9bfe2688acc0832f05000000001976a9147480a33f950689af511e6e84c138dbbd3c3ee41588ac00000000");
+    let witness_script = "56210307b8ae49ac90a048e9b53357a2354b3334e9c8bee813ecb98e99a7e07e8c3ba32103b28f0c28bfab54554ae8c658ac5c3e0ce6e79ad336331f78c428dd43eea8449b21034b8113d703413d57761b8b9781957b8c0ac1dfe69f492580ca4195f50376ba4a21033400f6afecb833092a9a21cfdf1ed1376e58c5d1f47de74683123987e967a8f42103a6d48b1131e94ba04d9737d61acdaa1322008af9602b3b14862c07a1789aac162102d8b661b0b3302ee2f162b09e07a55ad5dfbe673a9f01d9f0c19617681024306b56ae";
+    let vectors = [
+      (SIGHASH_ALL, "185c0be5263dce5b4bb50a047973c1b6272bfbd0103a89444597dc40b248ee7c"),
+      (SIGHASH_NONE, "e9733bc60ea13c95c6527066bb975a2ff29a925e80aa14c213f686cbae5d2f36"),
+      (SIGHASH_SINGLE, "1e1f1c303dc025bd664acb72e583e933fae4cff9148bf78c157d1e8f78530aea"),
+      (SIGHASH_ALL | SIGHASH_ANYONECANPAY, "2a67f03e63a6a422125878b40b82da593be8d4efaafe88ee528af6e5a9955c6e"),
+      (SIGHASH_NONE | SIGHASH_ANYONECANPAY, "781ba15f3779d5542ce8ecb5c18716733a5ee42a6f51488ec96154934e2c890a"),
+      (SIGHASH_SINGLE | SIGHASH_ANYONECANPAY, "511e8e52ed574121fc1b654970395502128263f62662e076dc6baf05c2e6a99b")
+    ];
+    for &(hash_type, expected) in vectors.iter() {
+      check_segwit_v0 (&tx, 0, witness_script, 987654321, hash_type, expected);
+    }
+  }
+
+  fn taproot_vector_tx () -> (Transaction, ~[TxOut]) {
+    let tx = tx_from_str ("02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c010000000000000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000fffffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a418420000000000fffffffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b0100000000feffffffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c0000000000feffffff956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd050000000000000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c94010000000000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf0000000000ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af10100000000ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac807840cb0000000020ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b0065cd1d");
+    let spent = [
+      ("512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343", 420000000),
+      ("5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3", 462000000),
+      ("76a914751e76e8199196d454941c45d1b3a323f1433bd688ac", 294000000),
+      ("5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e", 504000000),
+      ("512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605", 630000000),
+      ("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378000000),
+      ("512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831", 672000000),
+      ("5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5", 546000000),
+      ("512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220", 588000000)
+    ];
+    let spent: ~[TxOut] = spent.iter().map (|&(spk, sat)| {
+      TxOut { nValue: Amount::from_sat (sat).unwrap(), scriptPubKey: hex_string_to_u8 (spk).unwrap() }
+    }).collect();
+    (tx, spent)
+  }
+
+  /* From BIP341's keyPathSpending vectors */
+  #[test]
+  fn taproot_vectors() {
+    let (tx, spent) = taproot_vector_tx();
+    let vectors = [
+      (0, SIGHASH_SINGLE, "2514a6272f85cfa0f45eb907fcb0d121b808ed37c6ea160a5a9046ed5526d555"),
+      (1, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY, "325a644af47e8a5a2591cda0ab0723978537318f10e6a63d4eed783b96a71a4d"),
+      (3, SIGHASH_ALL, "bf013ea93474aa67815b1b6cc441d23b64fa310911d991e713cd34c7f5d46669"),
+      (4, SIGHASH_DEFAULT, "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef"),
+      (6, SIGHASH_NONE, "15f25c298eb5cdc7eb1d638dd2d45c97c4c59dcaec6679cfc16ad84f30876b85"),
+      (7, SIGHASH_NONE | SIGHASH_ANYONECANPAY, "cd292de50313804dabe4685e83f923d2969577191a3e1d2882220dca88cbeb10"),
+      (8, SIGHASH_ALL | SIGHASH_ANYONECANPAY, "cccb739eca6c13a8a89e6e5cd317ffe55669bbda23f2fd37b0f18755e008edd2")
+    ];
+    for &(index, hash_type, expected) in vectors.iter() {
+      let hash = tx.taproot_signature_hash (index, spent, hash_type, None).unwrap();
+      assert_eq! (u8_to_hex_string (hash), expected.to_owned());
+    }
+    /* Hash types BIP341 doesn't allow */
+    assert! (tx.taproot_signature_hash (0, spent, 0x04, None).is_none());
+    assert! (tx.taproot_signature_hash (0, spent, SIGHASH_ANYONECANPAY, None).is_none());
+  }
+
+  #[test]
+  fn taproot_key_spend() {
+    let (tx, spent) = taproot_vector_tx();
+    let mut txin = tx.input[0].clone();
+    txin.witness = ~[::std::vec::from_elem (64, 0x11u8)];
+    /* Without the spent output, this might be anything */
+    assert_eq! (txin.taproot_key_spend_hash_type(), None);
+    txin.prev_output = Some(spent[0].clone());
+    assert_eq! (txin.taproot_key_spend_hash_type(), Some(SIGHASH_DEFAULT));
+    txin.witness[0].push (SIGHASH_SINGLE | SIGHASH_ANYONECANPAY);
+    assert_eq! (txin.taproot_key_spend_hash_type(), Some(SIGHASH_SINGLE | SIGHASH_ANYONECANPAY));
+    /* An annex doesn't count as a second stack element */
+    txin.witness.push (~[0x50, 0x01]);
+    assert_eq! (txin.taproot_key_spend_hash_type(), Some(SIGHASH_SINGLE | SIGHASH_ANYONECANPAY));
+    /* Input 2 spends a P2PKH output */
+    txin.prev_output = Some(spent[2].clone());
+    assert_eq! (txin.taproot_key_spend_hash_type(), None);
+  }
+
+  /* The signed form of BIP143's native P2WPKH example: one legacy input and
+   * one P2WPKH input */
+  #[test]
+  fn sizes_and_ids() {
+    let tx = tx_from_str ("01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000");
+    assert_eq! (u8_to_hex_string (tx.to_hash()), ~"e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609");
+    assert_eq! (u8_to_hex_string (tx.wtxid()), ~"c36c38370907df2324d9ce9d149d191192f338b37665a82e78e76a12c909b762");
+    assert_eq! (tx.stripped_size(), 233);
+    assert_eq! (tx.total_size(), 343);
+    assert_eq! (tx.weight(), 1042);
+    assert_eq! (tx.vsize(), 261);
+
+    /* Without witnesses, the wtxid is the txid and weight is four times size */
+    let tx = tx_from_str ("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000");
+    assert_eq! (tx.wtxid(), tx.to_hash());
+    assert_eq! (tx.stripped_size(), tx.total_size());
+    assert_eq! (tx.weight(), 4 * tx.total_size());
+  }
+}
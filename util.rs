@@ -2,6 +2,8 @@
 use std::num::strconv::{from_str_bytes_common, ExpNone};
 use std::io::stdio::{stdin};
 use std::io::io_error;
+use std::io::File;
+use std::str;
 
 /**
  * Reads two characters from stdin, which we interpret as an 8-bit hex number
@@ -48,6 +50,74 @@ pub fn read_hex() -> ~[u8]
   rv
 }
 
+/**
+ * Reads a line from stdin, without its newline, or None at EOF
+ */
+pub fn read_line() -> Option<~str>
+{
+  let mut rv: ~[u8] = ~[];
+  let mut eof = false;
+  io_error::cond.trap(|_| ()).inside(|| {
+    let mut read_stream = stdin();
+    let mut read_buf: ~[u8] = ~[0];
+    loop {
+      match read_stream.read (read_buf) {
+        None => { eof = true; break }
+        Some(_) if read_buf[0] == '\n' as u8 => { break }
+        Some(_) => { rv.push (read_buf[0]); }
+      }
+    }
+  });
+  if eof && rv.len() == 0 {
+    return None;
+  }
+  match str::from_utf8_opt (rv) {
+    Some(s) => Some(s.trim_right_chars (&'\r').to_owned()),
+    None => Some(~"[invalid UTF-8]")
+  }
+}
+
+/**
+ * Reads the lines of a file, or None if it cannot be read
+ */
+pub fn read_lines (path: &str) -> Option<~[~str]>
+{
+  let mut contents = None;
+  io_error::cond.trap(|_| ()).inside(|| {
+    match File::open (&Path::new (path)) {
+      Some(mut f) => { contents = Some(f.read_to_end()); }
+      None => {}
+    }
+  });
+  match contents {
+    Some(bytes) => match str::from_utf8_opt (bytes) {
+      Some(s) => Some(s.lines().map (|l| l.to_owned()).collect()),
+      None => None
+    },
+    None => None
+  }
+}
+
+/**
+ * Converts a hexadecimal string to a bitstring, or None if it is not hex
+ */
+pub fn hex_string_to_u8 (s: &str) -> Option<~[u8]>
+{
+  let bytes = s.as_bytes();
+  if bytes.len() % 2 != 0 { return None; }
+
+  let mut rv: ~[u8] = ~[];
+  for pair in bytes.chunks (2) {
+    let hi = from_str_bytes_common (pair.slice (0, 1), 16, false, false, false, ExpNone, false, false);
+    let lo = from_str_bytes_common (pair.slice (1, 2), 16, false, false, false, ExpNone, false, false);
+    match (hi, lo) {
+      (Some(h), Some(l)) => { rv.push (16 * h + l); }
+      _ => { return None; }
+    }
+  }
+  Some(rv)
+}
+
 /**
  * Converts a bitstring to a hexadecimal string for user output
  */